@@ -0,0 +1,56 @@
+use crate::authstore::{channel_matches, Action};
+use crate::config::CONFIG;
+use crate::errors::QuotaError;
+use crate::quotas::Quota;
+use crate::scheduler::now_millis;
+use lazy_static::lazy_static;
+use smol::lock::Mutex;
+use std::collections::HashMap;
+
+pub static ANONYMOUS_OWNER: &str = "anonymous";
+
+lazy_static! {
+    static ref GUEST_RATES: Mutex<HashMap<u64, (u64, u64)>> = Mutex::new(HashMap::new());
+}
+
+pub fn anonymous_enabled() -> bool {
+    !CONFIG.anonymous_channels.is_empty()
+}
+
+pub fn public_pattern(action: Action, channel: &str) -> Option<&'static str> {
+    if action == Action::Pub {
+        return None;
+    }
+    CONFIG
+        .anonymous_channels
+        .iter()
+        .find(|pattern| channel_matches(pattern, channel))
+        .map(String::as_str)
+}
+
+pub fn guest_quota() -> Quota {
+    Quota {
+        max_connections: Some(CONFIG.anonymous_max_connections),
+        ..Quota::default()
+    }
+}
+
+pub async fn check_guest_rate(session_id: u64) -> Result<(), QuotaError> {
+    let now = now_millis();
+    let max_msgs = CONFIG.anonymous_max_msgs_per_sec;
+    let mut rates = GUEST_RATES.lock().await;
+    let (started_at, messages) = rates.entry(session_id).or_default();
+    if now >= *started_at + 1000 {
+        *started_at = now;
+        *messages = 0;
+    }
+    if *messages >= max_msgs {
+        return Err(QuotaError::RequestRate(max_msgs));
+    }
+    *messages += 1;
+    Ok(())
+}
+
+pub async fn end_guest_session(session_id: u64) {
+    GUEST_RATES.lock().await.remove(&session_id);
+}
@@ -0,0 +1,266 @@
+use crate::config::CONFIG;
+use crate::scheduler::now_millis;
+use crate::tokens::TokenGrant;
+use lazy_static::lazy_static;
+use rusqlite::{params, Connection, ToSql};
+use smol::channel::{bounded, Receiver, Sender};
+use smol::Timer;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+static CREATE_TABLE: &str = "CREATE TABLE IF NOT EXISTS audit_events (
+             id INTEGER PRIMARY KEY AUTOINCREMENT,
+             timestamp INTEGER NOT NULL,
+             session_id INTEGER,
+             owner TEXT,
+             remote_addr TEXT,
+             action TEXT NOT NULL,
+             channel TEXT,
+             outcome TEXT NOT NULL
+         );
+         CREATE INDEX IF NOT EXISTS audit_events_timestamp ON audit_events (timestamp);
+         CREATE INDEX IF NOT EXISTS audit_events_owner ON audit_events (owner, timestamp);";
+
+static INSERT: &str = "INSERT INTO audit_events
+             (timestamp, session_id, owner, remote_addr, action, channel, outcome)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7);";
+
+#[derive(Clone, Default)]
+pub struct AuditPeer {
+    pub session_id: Option<u64>,
+    pub owner: Option<String>,
+    pub remote: Option<SocketAddr>,
+    pub grant: Option<Arc<TokenGrant>>,
+    pub guest: bool,
+}
+
+pub struct AuditEvent {
+    pub timestamp: u64,
+    pub session_id: Option<u64>,
+    pub owner: Option<String>,
+    pub remote: Option<String>,
+    pub action: String,
+    pub channel: Option<String>,
+    pub outcome: String,
+}
+
+#[derive(Default)]
+pub struct AuditFilter {
+    pub owner: Option<String>,
+    pub action: Option<String>,
+    pub channel: Option<String>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub limit: Option<u64>,
+}
+
+static AUDIT_QUEUE_SIZE: usize = 10_000;
+static AUDIT_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+static AUDIT_ENABLED: AtomicBool = AtomicBool::new(false);
+static DROPPED_EVENTS: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref AUDIT_LOG: Mutex<Option<Connection>> = Mutex::new(None);
+    static ref AUDIT_QUEUE: (Sender<AuditEvent>, Receiver<AuditEvent>) = bounded(AUDIT_QUEUE_SIZE);
+}
+
+pub async fn feed_audit_log() -> Result<(), rusqlite::Error> {
+    let Some(path) = &CONFIG.audit_db else {
+        return Ok(());
+    };
+    let conn = Connection::open(path)?;
+    conn.execute_batch(CREATE_TABLE)?;
+    if let Some(retention) = CONFIG.audit_retention {
+        let pruned = prune_events(&conn, retention)?;
+        println!("Pruned {} audit events older than retention", pruned);
+    }
+    *AUDIT_LOG.lock().unwrap() = Some(conn);
+    AUDIT_ENABLED.store(true, Ordering::Relaxed);
+    println!("Writing audit events to {}", path);
+    Ok(())
+}
+
+pub fn audit(peer: &AuditPeer, action: &str, channel: Option<&str>, outcome: &str) {
+    if !AUDIT_ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let queued = AUDIT_QUEUE.0.try_send(AuditEvent {
+        timestamp: now_millis(),
+        session_id: peer.session_id,
+        owner: peer.owner.clone(),
+        remote: peer.remote.map(|remote| remote.to_string()),
+        action: action.to_owned(),
+        channel: channel.map(str::to_owned),
+        outcome: outcome.to_owned(),
+    });
+    if queued.is_err() {
+        DROPPED_EVENTS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub async fn run_audit_log() {
+    while let Ok(event) = AUDIT_QUEUE.1.recv().await {
+        let mut events = vec![event];
+        while let Ok(event) = AUDIT_QUEUE.1.try_recv() {
+            events.push(event);
+        }
+
+        let dropped = DROPPED_EVENTS.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            println!("Dropped {} audit events: audit queue is full", dropped);
+        }
+
+        let count = events.len();
+        let written = smol::unblock(move || match AUDIT_LOG.lock().unwrap().as_mut() {
+            Some(conn) => write_events(conn, &events),
+            None => Ok(()),
+        })
+        .await;
+        if let Err(e) = written {
+            println!("Failed to write {} audit events: {}", count, e);
+        }
+    }
+}
+
+pub async fn prune_audit_log(retention: Duration) {
+    loop {
+        Timer::after(AUDIT_PRUNE_INTERVAL).await;
+        let pruned = smol::unblock(move || match AUDIT_LOG.lock().unwrap().as_ref() {
+            Some(conn) => prune_events(conn, retention),
+            None => Ok(0),
+        })
+        .await;
+        match pruned {
+            Ok(0) => (),
+            Ok(pruned) => println!("Pruned {} audit events older than retention", pruned),
+            Err(e) => println!("Failed to prune audit events: {}", e),
+        }
+    }
+}
+
+fn prune_events(conn: &Connection, retention: Duration) -> Result<usize, rusqlite::Error> {
+    let cutoff = now_millis().saturating_sub(retention.as_millis() as u64);
+    conn.execute(
+        "DELETE FROM audit_events WHERE timestamp < ?1;",
+        [cutoff as i64],
+    )
+}
+
+fn write_events(conn: &mut Connection, events: &[AuditEvent]) -> Result<(), rusqlite::Error> {
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare_cached(INSERT)?;
+        for event in events {
+            stmt.execute(params![
+                event.timestamp as i64,
+                event.session_id.map(|session_id| session_id as i64),
+                event.owner,
+                event.remote,
+                event.action,
+                event.channel,
+                event.outcome
+            ])?;
+        }
+    }
+    tx.commit()
+}
+
+pub fn query_audit_log(
+    path: &str,
+    filter: &AuditFilter,
+) -> Result<Vec<AuditEvent>, rusqlite::Error> {
+    let conn = Connection::open(path)?;
+    conn.execute_batch(CREATE_TABLE)?;
+
+    let mut conditions = Vec::new();
+    let mut values: Vec<Box<dyn ToSql>> = Vec::new();
+    for (column, value) in [
+        ("owner", &filter.owner),
+        ("action", &filter.action),
+        ("channel", &filter.channel),
+    ] {
+        if let Some(value) = value {
+            values.push(Box::new(value.clone()));
+            conditions.push(format!("{} = ?{}", column, values.len()));
+        }
+    }
+    if let Some(since) = filter.since {
+        values.push(Box::new(since as i64));
+        conditions.push(format!("timestamp >= ?{}", values.len()));
+    }
+    if let Some(until) = filter.until {
+        values.push(Box::new(until as i64));
+        conditions.push(format!("timestamp < ?{}", values.len()));
+    }
+    let mut query = "SELECT timestamp, session_id, owner, remote_addr, action, channel, outcome
+             FROM audit_events"
+        .to_owned();
+    if !conditions.is_empty() {
+        query.push_str(&format!(" WHERE {}", conditions.join(" AND ")));
+    }
+    query.push_str(" ORDER BY timestamp DESC, id DESC");
+    if let Some(limit) = filter.limit {
+        query.push_str(&format!(" LIMIT {}", limit));
+    }
+
+    let mut stmt = conn.prepare(&query)?;
+    let rows = stmt.query_map(rusqlite::params_from_iter(values.iter()), |row| {
+        Ok(AuditEvent {
+            timestamp: row.get::<_, i64>(0)? as u64,
+            session_id: row
+                .get::<_, Option<i64>>(1)?
+                .map(|session_id| session_id as u64),
+            owner: row.get(2)?,
+            remote: row.get(3)?,
+            action: row.get(4)?,
+            channel: row.get(5)?,
+            outcome: row.get(6)?,
+        })
+    })?;
+    rows.collect()
+}
+
+pub fn parse_audit_filter(args: &[&String]) -> Result<AuditFilter, String> {
+    let mut filter = AuditFilter::default();
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("Missing value for {}", flag))?;
+        let number = || {
+            value
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid number for {}: {}", flag, value))
+        };
+        match flag.as_str() {
+            "--owner" => filter.owner = Some(value.to_string()),
+            "--action" => filter.action = Some(value.to_string()),
+            "--channel" => filter.channel = Some(value.to_string()),
+            "--since" => filter.since = Some(number()?),
+            "--until" => filter.until = Some(number()?),
+            "--limit" => filter.limit = Some(number()?),
+            _ => return Err(format!("Unknown option {}", flag)),
+        }
+    }
+    Ok(filter)
+}
+
+impl fmt::Display for AuditEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let session_id = self.session_id.map(|session_id| session_id.to_string());
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            self.timestamp,
+            session_id.as_deref().unwrap_or("-"),
+            self.owner.as_deref().unwrap_or("-"),
+            self.remote.as_deref().unwrap_or("-"),
+            self.action,
+            self.channel.as_deref().unwrap_or("-"),
+            self.outcome
+        )
+    }
+}
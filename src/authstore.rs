@@ -1,7 +1,23 @@
-use lazy_static::lazy_static;
+use crate::anonymous::{guest_quota, public_pattern, ANONYMOUS_OWNER};
+use crate::audit::AuditPeer;
+use crate::cidr::Cidr;
+use crate::config::BrokerConfig;
+use crate::errors::AuthStoreError;
+use crate::file_authstore::FileAuthStore;
+use crate::http_authstore::HttpAuthStore;
+use crate::memory_authstore::MemoryAuthStore;
+use crate::quotas::Quota;
+use crate::scheduler::now_millis;
+use crate::scram::ScramKeys;
+use crate::secrets::{fake_salt, Credential, StoredSecret, DEFAULT_ITERATIONS};
+use crate::sqlite_authstore::SqliteAuthStore;
+use futures::future::BoxFuture;
 use sha2::{Digest, Sha256};
-use smol::lock::RwLock;
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+use subtle::ConstantTimeEq;
 
 pub struct AuthDbObject {
     pub owner: String,
@@ -10,54 +26,335 @@ pub struct AuthDbObject {
     pub allow_pub: String,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Effect {
+    Allow,
+    Deny,
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Rule {
+    pub pattern: String,
+    pub effect: Effect,
+    pub role: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct NetworkRule {
+    pub network: Cidr,
+    pub effect: Effect,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    Pub,
+    Sub,
+}
+
+#[derive(PartialEq)]
 pub struct AuthObject {
-    pub secret: String,
-    pub allow_sub: HashSet<String>,
-    pub allow_pub: HashSet<String>,
+    pub secrets: Vec<Credential>,
+    pub sub_rules: Vec<Rule>,
+    pub pub_rules: Vec<Rule>,
+    pub networks: Vec<NetworkRule>,
+    pub quota: Quota,
+}
+
+impl AuthObject {
+    pub fn rules(&self, action: Action) -> &[Rule] {
+        match action {
+            Action::Pub => &self.pub_rules,
+            Action::Sub => &self.sub_rules,
+        }
+    }
+}
+pub type AuthFuture<'a, T> = BoxFuture<'a, Result<T, AuthStoreError>>;
+
+pub trait AuthBackend: Send + Sync {
+    fn credentials<'a>(&'a self, owner: &'a str) -> AuthFuture<'a, Vec<Credential>>;
+    fn knows_user<'a>(&'a self, owner: &'a str) -> AuthFuture<'a, bool> {
+        Box::pin(async move { Ok(!self.credentials(owner).await?.is_empty()) })
+    }
+    fn authenticate<'a>(
+        &'a self,
+        owner: &'a str,
+        nonce: &'a [u8],
+        user_sha: &'a [u8],
+    ) -> AuthFuture<'a, bool> {
+        Box::pin(async move {
+            let secrets = valid_secrets(self.credentials(owner).await?);
+            Ok(secrets.iter().any(|secret| match secret.proof_key() {
+                None => false,
+                Some(key) => generate_sha(key, nonce).ct_eq(user_sha).into(),
+            }))
+        })
+    }
+    fn authorize<'a>(
+        &'a self,
+        owner: &'a str,
+        action: Action,
+        channel: &'a str,
+    ) -> AuthFuture<'a, Option<Rule>>;
+    fn quota<'a>(&'a self, owner: &'a str) -> AuthFuture<'a, Quota>;
+    fn networks<'a>(&'a self, _owner: &'a str) -> AuthFuture<'a, Vec<NetworkRule>> {
+        Box::pin(async move { Ok(Vec::new()) })
+    }
+    fn reload(&self) -> AuthFuture<'_, Option<Vec<String>>>;
+}
+
+pub fn open_auth_backend(config: &BrokerConfig) -> Result<Arc<dyn AuthBackend>, AuthStoreError> {
+    match config.auth_backend.as_str() {
+        "sqlite" => Ok(Arc::new(SqliteAuthStore::open(&config.auth_db)?)),
+        "file" => Ok(Arc::new(FileAuthStore::open(&config.auth_file)?)),
+        "memory" => Ok(Arc::new(MemoryAuthStore::new(HashMap::new()))),
+        "http" => Ok(Arc::new(HttpAuthStore::open(
+            &config.auth_http_url,
+            config.auth_http_timeout,
+            config.auth_cache_ttl,
+        )?)),
+        backend => Err(AuthStoreError::ConfigError(format!(
+            "Unknown auth backend: {}",
+            backend
+        ))),
+    }
 }
-lazy_static! {
-    pub static ref AUTH_MAP: RwLock<HashMap<String, AuthObject>> =
-        RwLock::new(HashMap::with_capacity(32));
+
+pub fn modified_at(paths: &[&str]) -> Option<SystemTime> {
+    paths
+        .iter()
+        .filter_map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .max()
+}
+
+fn logged<T>(result: Result<T, AuthStoreError>, fallback: T) -> T {
+    match result {
+        Ok(value) => value,
+        Err(e) => {
+            println!("Auth backend error: {}", e);
+            fallback
+        }
+    }
 }
-pub trait AuthStoreSource {
-    async fn feed_cache(&self);
-    async fn update_cache(&self);
+
+fn valid_secrets(credentials: Vec<Credential>) -> Vec<StoredSecret> {
+    let now = now_millis();
+    credentials
+        .into_iter()
+        .filter(|credential| credential.is_valid_at(now))
+        .map(|credential| credential.secret)
+        .collect()
 }
 
 #[inline(always)]
-fn generate_sha(secret: &str, nonce: &[u8]) -> [u8; 32] {
+fn generate_sha(secret: &[u8], nonce: &[u8]) -> [u8; 32] {
     let mut hasher = Sha256::new();
 
     let mut hash = [0; 32];
     hasher.update(nonce);
-    hasher.update(secret.as_bytes());
+    hasher.update(secret);
     hash.copy_from_slice(hasher.finalize().as_slice());
     hash
 }
 
 #[inline(always)]
-pub async fn auth_user(owner: &str, nonce: &[u8], user_sha: &[u8]) -> bool {
-    let map = AUTH_MAP.read().await;
-    match map.get(owner) {
-        None => false,
-        Some(auth_object) => generate_sha(&auth_object.secret, nonce) == *user_sha,
+pub async fn auth_user(auth: &dyn AuthBackend, owner: &str, nonce: &[u8], user_sha: &[u8]) -> bool {
+    logged(auth.authenticate(owner, nonce, user_sha).await, false)
+}
+
+#[inline(always)]
+pub async fn auth_scram_keys(auth: &dyn AuthBackend, owner: &str) -> Vec<ScramKeys> {
+    valid_secrets(logged(auth.credentials(owner).await, Vec::new()))
+        .iter()
+        .filter_map(|secret| secret.scram_keys())
+        .collect()
+}
+
+#[inline(always)]
+pub async fn auth_salt(auth: &dyn AuthBackend, owner: &str) -> (u32, Vec<u8>) {
+    let secrets = valid_secrets(logged(auth.credentials(owner).await, Vec::new()));
+    match secrets.iter().find_map(|secret| secret.salt()) {
+        Some(salt) => salt,
+        None if secrets.is_empty() => (DEFAULT_ITERATIONS, fake_salt(owner)),
+        None => (0, Vec::new()),
+    }
+}
+
+#[inline(always)]
+pub async fn auth_known(auth: &dyn AuthBackend, owner: &str) -> bool {
+    logged(auth.knows_user(owner).await, false)
+}
+
+#[inline(always)]
+pub async fn auth_address(auth: &dyn AuthBackend, owner: &str, address: IpAddr) -> bool {
+    let networks = logged(auth.networks(owner).await.map(Some), None);
+    let Some(networks) = networks else {
+        return false;
+    };
+    let matching = networks
+        .iter()
+        .filter(|rule| rule.network.contains(address));
+    let mut allowed = !networks.iter().any(|rule| rule.effect == Effect::Allow);
+    for rule in matching {
+        if rule.effect == Effect::Deny {
+            return false;
+        }
+        allowed = true;
+    }
+    allowed
+}
+
+#[inline(always)]
+pub async fn user_quota(auth: &dyn AuthBackend, peer: &AuditPeer) -> Quota {
+    if peer.guest {
+        return guest_quota();
     }
+    let owner = peer.owner.as_deref().unwrap_or_default();
+    logged(auth.quota(owner).await, Quota::default())
 }
 
 #[inline(always)]
-pub async fn auth_pub(owner: &str, pub_chan: &str) -> bool {
-    let map = AUTH_MAP.read().await;
-    match map.get(owner) {
-        None => false,
-        Some(auth_object) => auth_object.allow_pub.contains(pub_chan),
+pub async fn auth_pub(auth: &dyn AuthBackend, peer: &AuditPeer, pub_chan: &str) -> bool {
+    if peer.guest {
+        return public_pattern(Action::Pub, pub_chan).is_some();
+    }
+    let owner = peer.owner.as_deref().unwrap_or_default();
+    match auth.authorize(owner, Action::Pub, pub_chan).await {
+        Ok(Some(rule)) => rule.effect == Effect::Allow,
+        Ok(None) => peer
+            .grant
+            .as_ref()
+            .is_some_and(|grant| grant.allows_pub(pub_chan)),
+        Err(e) => logged(Err(e), false),
     }
 }
 
 #[inline(always)]
-pub async fn auth_sub(owner: &str, sub_chan: &str) -> bool {
-    let map = AUTH_MAP.read().await;
-    match map.get(owner) {
-        None => false,
-        Some(auth_object) => auth_object.allow_sub.contains(sub_chan),
+pub async fn auth_sub(auth: &dyn AuthBackend, peer: &AuditPeer, sub_chan: &str) -> bool {
+    if peer.guest {
+        return public_pattern(Action::Sub, sub_chan).is_some();
+    }
+    let owner = peer.owner.as_deref().unwrap_or_default();
+    match auth.authorize(owner, Action::Sub, sub_chan).await {
+        Ok(Some(rule)) => rule.effect == Effect::Allow,
+        Ok(None) => peer
+            .grant
+            .as_ref()
+            .is_some_and(|grant| grant.allows_sub(sub_chan)),
+        Err(e) => logged(Err(e), false),
+    }
+}
+
+pub fn deciding_rule<'a>(rules: &'a [Rule], channel: &str) -> Option<&'a Rule> {
+    let mut allowed = None;
+    for rule in rules
+        .iter()
+        .filter(|rule| channel_matches(&rule.pattern, channel))
+    {
+        if rule.effect == Effect::Deny {
+            return Some(rule);
+        }
+        allowed.get_or_insert(rule);
+    }
+    allowed
+}
+
+pub async fn explain(
+    auth: &dyn AuthBackend,
+    owner: &str,
+    action: Action,
+    channel: &str,
+) -> Result<(bool, String), AuthStoreError> {
+    let rule = auth.authorize(owner, action, channel).await?;
+    let known = !auth.credentials(owner).await?.is_empty();
+
+    let explanation = match rule {
+        Some(rule) => {
+            let source = match &rule.role {
+                Some(role) => format!("role {}", role),
+                None => format!("user {}", owner),
+            };
+            match rule.effect {
+                Effect::Allow => (
+                    true,
+                    format!("Allowed by {} grant {}", source, rule.pattern),
+                ),
+                Effect::Deny => (
+                    false,
+                    format!("Denied by {} deny rule {}", source, rule.pattern),
+                ),
+            }
+        }
+        None if known => (false, format!("No grant of {} matches {}", owner, channel)),
+        None if owner == ANONYMOUS_OWNER => match public_pattern(action, channel) {
+            Some(pattern) => (true, format!("Allowed by public channel {}", pattern)),
+            None => (
+                false,
+                format!("Anonymous sessions may not access {}", channel),
+            ),
+        },
+        None => (false, format!("Unknown user {}", owner)),
+    };
+    Ok(explanation)
+}
+
+pub fn channel_matches(pattern: &str, channel: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == channel,
+        Some((prefix, rest)) => channel.strip_prefix(prefix).is_some_and(|tail| {
+            (0..=tail.len())
+                .filter(|i| tail.is_char_boundary(*i))
+                .any(|i| channel_matches(rest, &tail[i..]))
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(networks: &[(&str, Effect)]) -> MemoryAuthStore {
+        let networks = networks
+            .iter()
+            .map(|(cidr, effect)| NetworkRule {
+                network: Cidr::parse(cidr).unwrap(),
+                effect: *effect,
+            })
+            .collect();
+        MemoryAuthStore::new(HashMap::from([(
+            "kitek".to_owned(),
+            AuthObject {
+                secrets: Vec::new(),
+                sub_rules: Vec::new(),
+                pub_rules: Vec::new(),
+                networks,
+                quota: Quota::default(),
+            },
+        )]))
+    }
+
+    async fn permitted(auth: &MemoryAuthStore, address: &str) -> bool {
+        auth_address(auth, "kitek", address.parse().unwrap()).await
+    }
+
+    #[test]
+    fn address_rules_deny_over_allow() {
+        smol::block_on(async {
+            let unrestricted = store(&[]);
+            assert!(permitted(&unrestricted, "203.0.113.7").await);
+
+            let allow_only = store(&[("10.0.0.0/8", Effect::Allow)]);
+            assert!(permitted(&allow_only, "10.1.2.3").await);
+            assert!(!permitted(&allow_only, "192.168.1.1").await);
+
+            let deny_only = store(&[("10.1.0.0/16", Effect::Deny)]);
+            assert!(!permitted(&deny_only, "10.1.2.3").await);
+            assert!(permitted(&deny_only, "10.2.0.1").await);
+
+            let both = store(&[("10.0.0.0/8", Effect::Allow), ("10.1.0.0/16", Effect::Deny)]);
+            assert!(permitted(&both, "10.2.0.1").await);
+            assert!(!permitted(&both, "10.1.2.3").await);
+            assert!(!permitted(&both, "::ffff:10.1.2.3").await);
+            assert!(!permitted(&both, "192.168.1.1").await);
+        });
     }
 }
@@ -0,0 +1,122 @@
+use crate::channel_config::{channel_config, BackpressureMode};
+use crate::messaging::throttle_frame;
+use crate::server::SUBS;
+use crate::transport::ClientWriter;
+use lazy_static::lazy_static;
+use smol::{io::AsyncWriteExt, lock::Mutex};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+static DEFAULT_HIGH_WATERMARK: usize = 1024;
+
+#[derive(Default)]
+struct ChannelPressure {
+    pending_acks: Vec<(Arc<Mutex<ClientWriter>>, Vec<u8>)>,
+    throttled: Vec<Arc<Mutex<ClientWriter>>>,
+}
+
+lazy_static! {
+    static ref PRESSURE: Mutex<HashMap<String, ChannelPressure>> = Mutex::new(HashMap::new());
+}
+
+async fn channel_depth(channel: &str) -> usize {
+    let subs_map = SUBS.read().await;
+    let Some(subs) = subs_map.get(channel) else {
+        return 0;
+    };
+    let mut depth = 0;
+    for subscription in subs.values() {
+        depth = depth.max(subscription.queue_len().await);
+    }
+    depth
+}
+
+pub async fn apply_backpressure(
+    channel: &str,
+    writer: &Arc<Mutex<ClientWriter>>,
+    ack: Option<Vec<u8>>,
+) -> Option<Vec<u8>> {
+    let config = channel_config(channel).await;
+    let Some(mode) = config.backpressure else {
+        return ack;
+    };
+    let high_watermark = config.high_watermark.unwrap_or(DEFAULT_HIGH_WATERMARK);
+    let depth = channel_depth(channel).await;
+
+    let mut throttle = false;
+    let ack = {
+        let mut pressure = PRESSURE.lock().await;
+        if !pressure.contains_key(channel) {
+            if depth < high_watermark {
+                return ack;
+            }
+            println!(
+                "Applying backpressure on {} (queue depth {})",
+                channel, depth
+            );
+            pressure.insert(channel.to_owned(), ChannelPressure::default());
+        }
+        let state = pressure.get_mut(channel).unwrap();
+        match (mode, ack) {
+            (BackpressureMode::WithholdAck, Some(ack)) => {
+                state.pending_acks.push((writer.clone(), ack));
+                None
+            }
+            (_, ack) => {
+                if !state
+                    .throttled
+                    .iter()
+                    .any(|throttled| Arc::ptr_eq(throttled, writer))
+                {
+                    state.throttled.push(writer.clone());
+                    throttle = true;
+                }
+                ack
+            }
+        }
+    };
+
+    if throttle {
+        let mut stream = writer.lock().await;
+        let _ = stream.write_all(&throttle_frame(channel, true)).await;
+    }
+    ack
+}
+
+pub async fn release_backpressure(channel: &str) {
+    let config = channel_config(channel).await;
+    let high_watermark = config.high_watermark.unwrap_or(DEFAULT_HIGH_WATERMARK);
+    let low_watermark = config.low_watermark.unwrap_or(high_watermark / 2);
+    if !PRESSURE.lock().await.contains_key(channel) {
+        return;
+    }
+    if channel_depth(channel).await > low_watermark {
+        return;
+    }
+
+    let Some(state) = PRESSURE.lock().await.remove(channel) else {
+        return;
+    };
+    println!("Released backpressure on {}", channel);
+    for (writer, ack) in state.pending_acks {
+        let mut stream = writer.lock().await;
+        let _ = stream.write_all(&ack).await;
+    }
+    let resume = throttle_frame(channel, false);
+    for writer in state.throttled {
+        let mut stream = writer.lock().await;
+        let _ = stream.write_all(&resume).await;
+    }
+}
+
+pub async fn forget_writer(writer: &Arc<Mutex<ClientWriter>>) {
+    let mut pressure = PRESSURE.lock().await;
+    for state in pressure.values_mut() {
+        state
+            .pending_acks
+            .retain(|(pending, _)| !Arc::ptr_eq(pending, writer));
+        state
+            .throttled
+            .retain(|throttled| !Arc::ptr_eq(throttled, writer));
+    }
+}
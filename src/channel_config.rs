@@ -0,0 +1,126 @@
+use lazy_static::lazy_static;
+use rusqlite::Connection;
+use smol::lock::RwLock;
+use std::collections::HashMap;
+use std::time::Duration;
+
+pub static CHANNEL_DB_PATH: &str = "./sqlite/channels.db";
+
+static CREATE_TABLE: &str = "CREATE TABLE IF NOT EXISTS channel_options (
+             channel TEXT NOT NULL,
+             option TEXT NOT NULL,
+             value TEXT NOT NULL
+         );";
+
+static QUERY: &str = "SELECT channel, option, value
+             FROM channel_options;";
+
+#[derive(Clone, Copy)]
+pub enum BackpressureMode {
+    WithholdAck,
+    Throttle,
+}
+
+#[derive(Clone, Default)]
+pub struct ChannelConfig {
+    pub dedup_window: Option<Duration>,
+    pub dedup_count: Option<usize>,
+    pub expiry_channel: Option<String>,
+    pub dead_letter_channel: Option<String>,
+    pub backpressure: Option<BackpressureMode>,
+    pub high_watermark: Option<usize>,
+    pub low_watermark: Option<usize>,
+    pub max_queue_depth: Option<usize>,
+    pub conflation_header: Option<String>,
+    pub conflation_field: Option<String>,
+    pub snapshot: bool,
+}
+
+lazy_static! {
+    pub static ref CHANNEL_CONFIG: RwLock<HashMap<String, ChannelConfig>> =
+        RwLock::new(HashMap::new());
+}
+
+pub async fn channel_config(channel: &str) -> ChannelConfig {
+    let map = CHANNEL_CONFIG.read().await;
+    map.get(channel).cloned().unwrap_or_default()
+}
+
+pub async fn feed_channel_config(path: &str) -> Result<(), rusqlite::Error> {
+    let conn = Connection::open(path)?;
+    conn.execute(CREATE_TABLE, [])?;
+
+    let mut stmt = conn.prepare(QUERY)?;
+    let options = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+        ))
+    })?;
+
+    let mut map = CHANNEL_CONFIG.write().await;
+    for option in options {
+        let (channel, option, value) = option?;
+        let config = map.entry(channel.clone()).or_default();
+        if !apply_option(config, &option, &value) {
+            println!(
+                "Ignoring channel option {}={} for channel {}",
+                option, value, channel
+            );
+        }
+    }
+    for (channel, config) in map.iter_mut() {
+        if config.snapshot
+            && config.conflation_header.is_none()
+            && config.conflation_field.is_none()
+        {
+            println!(
+                "Ignoring channel option snapshot=true for channel {}: it needs conflation_header or conflation_field",
+                channel
+            );
+            config.snapshot = false;
+        }
+    }
+    Ok(())
+}
+
+fn apply_option(config: &mut ChannelConfig, option: &str, value: &str) -> bool {
+    match option {
+        "dedup_window_ms" => match value.parse() {
+            Ok(ms) => config.dedup_window = Some(Duration::from_millis(ms)),
+            Err(_) => return false,
+        },
+        "dedup_window_count" => match value.parse() {
+            Ok(count) => config.dedup_count = Some(count),
+            Err(_) => return false,
+        },
+        "expiry_channel" => config.expiry_channel = Some(value.to_owned()),
+        "dead_letter_channel" => config.dead_letter_channel = Some(value.to_owned()),
+        "backpressure" => match value {
+            "withhold_ack" => config.backpressure = Some(BackpressureMode::WithholdAck),
+            "throttle" => config.backpressure = Some(BackpressureMode::Throttle),
+            _ => return false,
+        },
+        "high_watermark" => match value.parse() {
+            Ok(high_watermark) => config.high_watermark = Some(high_watermark),
+            Err(_) => return false,
+        },
+        "low_watermark" => match value.parse() {
+            Ok(low_watermark) => config.low_watermark = Some(low_watermark),
+            Err(_) => return false,
+        },
+        "max_queue_depth" => match value.parse() {
+            Ok(max_queue_depth) => config.max_queue_depth = Some(max_queue_depth),
+            Err(_) => return false,
+        },
+        "conflation_header" => config.conflation_header = Some(value.to_owned()),
+        "conflation_field" => config.conflation_field = Some(value.to_owned()),
+        "snapshot" => match value.parse() {
+            Ok(snapshot) => config.snapshot = snapshot,
+            Err(_) => return false,
+        },
+        _ => return false,
+    }
+    true
+}
@@ -0,0 +1,145 @@
+use lazy_static::lazy_static;
+use std::env;
+use std::time::Duration;
+
+pub struct BrokerConfig {
+    pub auth_backend: String,
+    pub auth_db: String,
+    pub auth_file: String,
+    pub auth_http_url: String,
+    pub auth_http_timeout: Duration,
+    pub auth_cache_ttl: Duration,
+    pub auth_reload_interval: Option<Duration>,
+    pub schedule_db: Option<String>,
+    pub audit_db: Option<String>,
+    pub audit_retention: Option<Duration>,
+    pub token_hmac_secrets: Vec<String>,
+    pub token_public_keys: Vec<String>,
+    pub token_issuer: Option<String>,
+    pub auth_failure_threshold: u32,
+    pub auth_lockout: Duration,
+    pub auth_lockout_max: Duration,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub tls_client_ca: Option<String>,
+    pub tls_crls: Vec<String>,
+    pub tls_identity: String,
+    pub tls_client_auth: String,
+    pub anonymous_channels: Vec<String>,
+    pub anonymous_max_connections: u64,
+    pub anonymous_max_msgs_per_sec: u64,
+    pub deny_networks: Vec<String>,
+    pub max_connections_per_ip: Option<u64>,
+    pub max_frame_size: u32,
+    pub stats_channel: Option<String>,
+    pub stats_interval: Duration,
+}
+
+lazy_static! {
+    pub static ref CONFIG: BrokerConfig = BrokerConfig::from_env();
+}
+
+impl BrokerConfig {
+    fn from_env() -> BrokerConfig {
+        BrokerConfig {
+            auth_backend: env::var("RUST_FEEDS_AUTH_BACKEND").unwrap_or("sqlite".to_owned()),
+            auth_db: env::var("RUST_FEEDS_AUTH_DB").unwrap_or("./sqlite/auth.db".to_owned()),
+            auth_file: env::var("RUST_FEEDS_AUTH_FILE").unwrap_or("./auth.json".to_owned()),
+            auth_http_url: env::var("RUST_FEEDS_AUTH_HTTP_URL")
+                .unwrap_or("http://127.0.0.1:8181/authorize".to_owned()),
+            auth_http_timeout: Duration::from_millis(
+                env::var("RUST_FEEDS_AUTH_HTTP_TIMEOUT_MS")
+                    .ok()
+                    .and_then(|millis| millis.parse().ok())
+                    .unwrap_or(2000),
+            ),
+            auth_cache_ttl: Duration::from_secs(
+                env::var("RUST_FEEDS_AUTH_CACHE_TTL_SECS")
+                    .ok()
+                    .and_then(|secs| secs.parse().ok())
+                    .unwrap_or(60),
+            ),
+            auth_reload_interval: match env::var("RUST_FEEDS_AUTH_RELOAD_SECS") {
+                Ok(secs) => secs
+                    .parse()
+                    .ok()
+                    .filter(|secs| *secs > 0)
+                    .map(Duration::from_secs),
+                Err(_) => Some(Duration::from_secs(5)),
+            },
+            schedule_db: env::var("RUST_FEEDS_SCHEDULE_DB").ok(),
+            audit_db: env::var("RUST_FEEDS_AUDIT_DB").ok(),
+            audit_retention: env::var("RUST_FEEDS_AUDIT_RETENTION_DAYS")
+                .ok()
+                .and_then(|days| days.parse::<u64>().ok())
+                .filter(|days| *days > 0)
+                .map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+            token_hmac_secrets: list_var("RUST_FEEDS_TOKEN_HMAC_SECRETS"),
+            token_public_keys: list_var("RUST_FEEDS_TOKEN_PUBLIC_KEYS"),
+            token_issuer: env::var("RUST_FEEDS_TOKEN_ISSUER").ok(),
+            auth_failure_threshold: env::var("RUST_FEEDS_AUTH_FAILURE_THRESHOLD")
+                .ok()
+                .and_then(|threshold| threshold.parse().ok())
+                .filter(|threshold| *threshold > 0)
+                .unwrap_or(5),
+            auth_lockout: Duration::from_secs(
+                env::var("RUST_FEEDS_AUTH_LOCKOUT_SECS")
+                    .ok()
+                    .and_then(|secs| secs.parse().ok())
+                    .unwrap_or(30),
+            ),
+            auth_lockout_max: Duration::from_secs(
+                env::var("RUST_FEEDS_AUTH_LOCKOUT_MAX_SECS")
+                    .ok()
+                    .and_then(|secs| secs.parse().ok())
+                    .unwrap_or(3600),
+            ),
+            tls_cert: env::var("RUST_FEEDS_TLS_CERT").ok(),
+            tls_key: env::var("RUST_FEEDS_TLS_KEY").ok(),
+            tls_client_ca: env::var("RUST_FEEDS_TLS_CLIENT_CA").ok(),
+            tls_crls: list_var("RUST_FEEDS_TLS_CRLS"),
+            tls_identity: env::var("RUST_FEEDS_TLS_IDENTITY").unwrap_or("san".to_owned()),
+            tls_client_auth: env::var("RUST_FEEDS_TLS_CLIENT_AUTH")
+                .unwrap_or("optional".to_owned()),
+            anonymous_channels: list_var("RUST_FEEDS_ANONYMOUS_CHANNELS"),
+            anonymous_max_connections: env::var("RUST_FEEDS_ANONYMOUS_MAX_CONNECTIONS")
+                .ok()
+                .and_then(|max| max.parse().ok())
+                .unwrap_or(100),
+            anonymous_max_msgs_per_sec: env::var("RUST_FEEDS_ANONYMOUS_MAX_MSGS_PER_SEC")
+                .ok()
+                .and_then(|max| max.parse().ok())
+                .filter(|max| *max > 0)
+                .unwrap_or(10),
+            deny_networks: list_var("RUST_FEEDS_DENY_NETWORKS"),
+            max_connections_per_ip: env::var("RUST_FEEDS_MAX_CONNECTIONS_PER_IP")
+                .ok()
+                .and_then(|max| max.parse().ok()),
+            max_frame_size: env::var("RUST_FEEDS_MAX_FRAME_SIZE")
+                .ok()
+                .and_then(|max| max.parse().ok())
+                .unwrap_or(16 * 1024 * 1024),
+            stats_channel: env::var("RUST_FEEDS_STATS_CHANNEL").ok(),
+            stats_interval: Duration::from_secs(
+                env::var("RUST_FEEDS_STATS_INTERVAL_SECS")
+                    .ok()
+                    .and_then(|secs| secs.parse().ok())
+                    .filter(|secs| *secs > 0)
+                    .unwrap_or(10),
+            ),
+        }
+    }
+}
+
+fn list_var(name: &str) -> Vec<String> {
+    env::var(name)
+        .map(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(str::to_owned)
+                .collect()
+        })
+        .unwrap_or_default()
+}
@@ -0,0 +1,22 @@
+use crate::channel_config::ChannelConfig;
+use crate::messaging::split_publish_frame;
+
+pub fn conflation_key(config: &ChannelConfig, frame: &[u8]) -> Option<String> {
+    if config.conflation_header.is_none() && config.conflation_field.is_none() {
+        return None;
+    }
+    let (headers, payload) = split_publish_frame(frame).ok()?;
+
+    if let Some(header) = &config.conflation_header {
+        if let Some(key) = headers.as_ref().and_then(|headers| headers.get(header)) {
+            return Some(key.to_owned());
+        }
+    }
+
+    let field = config.conflation_field.as_ref()?;
+    let payload: serde_json::Value = serde_json::from_slice(payload).ok()?;
+    match payload.get(field)? {
+        serde_json::Value::String(key) => Some(key.clone()),
+        key => Some(key.to_string()),
+    }
+}
@@ -0,0 +1,53 @@
+use crate::channel_config::channel_config;
+use crate::messaging::{deliver_to_subscribers, dropped_message_frame};
+use crate::subscription::OutboundMessage;
+use std::sync::Arc;
+
+#[derive(Clone, Copy)]
+pub enum DropReason {
+    NoSubscribers,
+    DeliveryFailed,
+    Expired,
+    SlowConsumer,
+}
+
+impl DropReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DropReason::NoSubscribers => "no_subscribers",
+            DropReason::DeliveryFailed => "delivery_failed",
+            DropReason::Expired => "expired",
+            DropReason::SlowConsumer => "slow_consumer",
+        }
+    }
+}
+
+pub async fn dead_letter(channel: &str, reason: DropReason, frame: &[u8]) {
+    let config = channel_config(channel).await;
+    if let Some(dead_letter_channel) = config.dead_letter_channel {
+        route_dropped(&dead_letter_channel, channel, reason, frame).await;
+    }
+}
+
+pub async fn route_dropped(target: &str, channel: &str, reason: DropReason, frame: &[u8]) {
+    if target == channel {
+        return;
+    }
+    let envelope = dropped_message_frame(target, channel, reason, frame);
+    let envelope = [Arc::new(OutboundMessage::new(envelope, None))];
+    match deliver_to_subscribers(target, &envelope).await {
+        None => println!(
+            "Dropped {} message from {}: no subscribers on {}",
+            reason.as_str(),
+            channel,
+            target
+        ),
+        Some(overflow) if !overflow.is_empty() => println!(
+            "Dropped {} message from {}: queue of a subscriber on {} is full",
+            reason.as_str(),
+            channel,
+            target
+        ),
+        Some(_) => (),
+    }
+}
@@ -0,0 +1,62 @@
+use crate::channel_config::ChannelConfig;
+use lazy_static::lazy_static;
+use smol::lock::Mutex;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::Instant;
+
+#[derive(Default)]
+struct DedupWindow {
+    seen: HashSet<String>,
+    order: VecDeque<(Instant, String)>,
+}
+
+lazy_static! {
+    static ref DEDUP: Mutex<HashMap<String, DedupWindow>> = Mutex::new(HashMap::new());
+}
+
+impl DedupWindow {
+    fn evict(&mut self, config: &ChannelConfig, now: Instant) {
+        while let Some((seen_at, message_id)) = self.order.front() {
+            let too_old = config
+                .dedup_window
+                .is_some_and(|window| now.duration_since(*seen_at) > window);
+            let too_many = config
+                .dedup_count
+                .is_some_and(|count| self.order.len() > count);
+            if !too_old && !too_many {
+                break;
+            }
+            self.seen.remove(message_id);
+            self.order.pop_front();
+        }
+    }
+}
+
+pub async fn is_duplicate(channel: &str, message_id: &str, config: &ChannelConfig) -> bool {
+    if config.dedup_window.is_none() && config.dedup_count.is_none() {
+        return false;
+    }
+
+    let now = Instant::now();
+    let mut dedup = DEDUP.lock().await;
+    let window = dedup.entry(channel.to_owned()).or_default();
+    window.evict(config, now);
+
+    if window.seen.contains(message_id) {
+        return true;
+    }
+    window.seen.insert(message_id.to_owned());
+    window.order.push_back((now, message_id.to_owned()));
+    window.evict(config, now);
+    false
+}
+
+pub async fn forget_message_id(channel: &str, message_id: &str) {
+    let mut dedup = DEDUP.lock().await;
+    let Some(window) = dedup.get_mut(channel) else {
+        return;
+    };
+    if window.seen.remove(message_id) {
+        window.order.retain(|(_, seen)| seen != message_id);
+    }
+}
@@ -1,3 +1,4 @@
+use std::net::IpAddr;
 use thiserror::Error;
 
 #[derive(Debug, Clone, Error)]
@@ -8,18 +9,70 @@ pub enum AuthError {
     UnauthSub(String),
 }
 
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Error)]
+pub enum AuthStoreError {
+    #[error(transparent)]
+    SqliteError(#[from] rusqlite::Error),
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    #[error("Invalid auth file: {}", .0)]
+    ParseError(String),
+    #[error("{}", .0)]
+    ConfigError(String),
+    #[error("Authorization callout failed: {}", .0)]
+    CalloutError(String),
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    RustlsError(#[from] futures_rustls::rustls::Error),
+    #[error(transparent)]
+    VerifierError(#[from] futures_rustls::rustls::server::VerifierBuilderError),
+    #[error("{}", .0)]
+    ConfigError(String),
+}
+
+#[derive(Debug, Clone, Error)]
+pub enum QuotaError {
+    #[error("Connection limit of {} reached", .0)]
+    ConnectionLimit(u64),
+    #[error("Connection limit of {} reached for address {}", .0, .1)]
+    AddressConnectionLimit(u64, IpAddr),
+    #[error("Subscription limit of {} reached", .0)]
+    SubscriptionLimit(u64),
+    #[error("Publish rate limit of {} messages per second exceeded", .0)]
+    MessageRate(u64),
+    #[error("Publish rate limit of {} bytes per second exceeded", .0)]
+    ByteRate(u64),
+    #[error("Message of {} bytes exceeds the limit of {} bytes", .0, .1)]
+    MessageSize(u64, u64),
+    #[error("Request rate limit of {} messages per second exceeded", .0)]
+    RequestRate(u64),
+}
+
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Error)]
 pub enum PublishError {
     #[error(transparent)]
     IoError(#[from] std::io::Error),
     #[error(transparent)]
     AuthError(#[from] AuthError),
+    #[error(transparent)]
+    QuotaError(#[from] QuotaError),
 }
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Error)]
 pub enum SubscribeError {
     #[error(transparent)]
     IoError(#[from] std::io::Error),
     #[error(transparent)]
     AuthError(#[from] AuthError),
+    #[error(transparent)]
+    QuotaError(#[from] QuotaError),
 }
@@ -0,0 +1,69 @@
+use crate::channel_config::channel_config;
+use crate::dead_letter::{route_dropped, DropReason};
+use crate::headers::Headers;
+use crate::messaging::{broker_publish_frame, write_to_subscribers};
+use crate::scheduler::now_millis;
+use crate::subscription::OutboundMessage;
+use lazy_static::lazy_static;
+use serde_json::json;
+use smol::lock::Mutex;
+use smol::Timer;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+lazy_static! {
+    static ref EXPIRED: Mutex<HashMap<String, u64>> = Mutex::new(HashMap::new());
+}
+
+pub fn expiry_time(headers: &Headers) -> Result<Option<u64>, std::io::Error> {
+    let expires_at = match (headers.get("expires_at"), headers.get("ttl")) {
+        (Some(expires_at), _) => expires_at.parse::<u64>(),
+        (None, Some(ttl)) => ttl
+            .parse::<u64>()
+            .map(|ttl| now_millis().saturating_add(ttl)),
+        (None, None) => return Ok(None),
+    };
+    match expires_at {
+        Ok(expires_at) => Ok(Some(expires_at)),
+        Err(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Invalid expiry time",
+        )),
+    }
+}
+
+pub fn is_expired(expires_at: Option<u64>) -> bool {
+    expires_at.is_some_and(|expires_at| expires_at <= now_millis())
+}
+
+pub async fn expire_message(channel: &str, frame: &[u8]) {
+    let expired_count = {
+        let mut expired = EXPIRED.lock().await;
+        let count = expired.entry(channel.to_owned()).or_default();
+        *count += 1;
+        *count
+    };
+    println!(
+        "Dropped expired message on {} ({} expired)",
+        channel, expired_count
+    );
+
+    let config = channel_config(channel).await;
+    if let Some(expiry_channel) = config.expiry_channel {
+        route_dropped(&expiry_channel, channel, DropReason::Expired, frame).await;
+    }
+}
+
+pub async fn expired_stats() -> serde_json::Value {
+    json!({ "expired": *EXPIRED.lock().await })
+}
+
+pub async fn publish_expired_stats(channel: String, interval: Duration) {
+    loop {
+        Timer::after(interval).await;
+        let payload = expired_stats().await.to_string();
+        let frame = broker_publish_frame(&channel, payload.as_bytes());
+        write_to_subscribers(&channel, &[Arc::new(OutboundMessage::new(frame, None))]).await;
+    }
+}
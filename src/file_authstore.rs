@@ -0,0 +1,287 @@
+use crate::authstore::{
+    modified_at, Action, AuthBackend, AuthFuture, AuthObject, Effect, NetworkRule, Rule,
+};
+use crate::cidr::Cidr;
+use crate::errors::AuthStoreError;
+use crate::memory_authstore::MemoryAuthStore;
+use crate::quotas::Quota;
+use crate::secrets::{Credential, SecretSlot, StoredSecret};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct FileGrants {
+    allow_pub: Vec<String>,
+    allow_sub: Vec<String>,
+    deny_pub: Vec<String>,
+    deny_sub: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct FileSecret {
+    secret: String,
+    valid_from: Option<u64>,
+    valid_until: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct FileUser {
+    #[serde(flatten)]
+    primary: FileSecret,
+    secondary: Option<FileSecret>,
+    #[serde(default)]
+    roles: Vec<String>,
+    #[serde(flatten)]
+    grants: FileGrants,
+    #[serde(default)]
+    allow_from: Vec<String>,
+    #[serde(default)]
+    deny_from: Vec<String>,
+    #[serde(default)]
+    quota: Quota,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct AuthFile {
+    users: HashMap<String, FileUser>,
+    roles: HashMap<String, FileGrants>,
+}
+
+impl FileSecret {
+    fn credential(&self, slot: SecretSlot) -> Result<Credential, String> {
+        Ok(Credential {
+            slot,
+            secret: StoredSecret::parse(&self.secret)?,
+            valid_from: self.valid_from,
+            valid_until: self.valid_until,
+        })
+    }
+}
+
+impl FileGrants {
+    fn push_rules(&self, role: Option<&str>, auth_object: &mut AuthObject) {
+        let rules = [
+            (&self.allow_pub, Action::Pub, Effect::Allow),
+            (&self.allow_sub, Action::Sub, Effect::Allow),
+            (&self.deny_pub, Action::Pub, Effect::Deny),
+            (&self.deny_sub, Action::Sub, Effect::Deny),
+        ];
+        for (patterns, action, effect) in rules {
+            for pattern in patterns {
+                let rule = Rule {
+                    pattern: pattern.clone(),
+                    effect,
+                    role: role.map(str::to_owned),
+                };
+                match action {
+                    Action::Pub => auth_object.pub_rules.push(rule),
+                    Action::Sub => auth_object.sub_rules.push(rule),
+                }
+            }
+        }
+    }
+}
+
+pub struct FileAuthStore {
+    path: String,
+    cache: MemoryAuthStore,
+    last_modified: Mutex<Option<SystemTime>>,
+}
+
+impl FileAuthStore {
+    pub fn open(path: &str) -> Result<FileAuthStore, AuthStoreError> {
+        let store = FileAuthStore {
+            path: path.to_owned(),
+            cache: MemoryAuthStore::new(HashMap::new()),
+            last_modified: Mutex::new(modified_at(&[path])),
+        };
+        Ok(FileAuthStore {
+            cache: MemoryAuthStore::new(store.load_auth_objects()?),
+            ..store
+        })
+    }
+
+    fn load_auth_objects(&self) -> Result<HashMap<String, AuthObject>, AuthStoreError> {
+        let contents = std::fs::read_to_string(&self.path)?;
+        let auth_file: AuthFile = if self.path.ends_with(".toml") {
+            toml::from_str(&contents).map_err(|e| AuthStoreError::ParseError(e.to_string()))?
+        } else {
+            serde_json::from_str(&contents)
+                .map_err(|e| AuthStoreError::ParseError(e.to_string()))?
+        };
+
+        let mut auth_objects = HashMap::with_capacity(auth_file.users.len());
+        for (owner, user) in auth_file.users {
+            let secondary = user
+                .secondary
+                .as_ref()
+                .map(|secondary| secondary.credential(SecretSlot::Secondary));
+            let secrets = match (user.primary.credential(SecretSlot::Primary), secondary) {
+                (Ok(primary), None) => vec![primary],
+                (Ok(primary), Some(Ok(secondary))) => vec![primary, secondary],
+                (Err(e), _) | (_, Some(Err(e))) => {
+                    println!("Skipping user {}: {}", owner, e);
+                    continue;
+                }
+            };
+            let mut auth_object = AuthObject {
+                secrets,
+                sub_rules: Vec::new(),
+                pub_rules: Vec::new(),
+                networks: Vec::new(),
+                quota: user.quota,
+            };
+            for (cidrs, effect) in [
+                (&user.allow_from, Effect::Allow),
+                (&user.deny_from, Effect::Deny),
+            ] {
+                for cidr in cidrs {
+                    match Cidr::parse(cidr) {
+                        Ok(network) => auth_object.networks.push(NetworkRule { network, effect }),
+                        Err(e) => println!("Skipping network of user {}: {}", owner, e),
+                    }
+                }
+            }
+            user.grants.push_rules(None, &mut auth_object);
+            for role in &user.roles {
+                match auth_file.roles.get(role) {
+                    Some(grants) => grants.push_rules(Some(role), &mut auth_object),
+                    None => println!("User {} references unknown role {}", owner, role),
+                }
+            }
+            auth_object.pub_rules.sort();
+            auth_object.sub_rules.sort();
+            auth_object.networks.sort();
+            auth_objects.insert(owner, auth_object);
+        }
+        Ok(auth_objects)
+    }
+}
+
+impl AuthBackend for FileAuthStore {
+    fn credentials<'a>(&'a self, owner: &'a str) -> AuthFuture<'a, Vec<Credential>> {
+        self.cache.credentials(owner)
+    }
+
+    fn knows_user<'a>(&'a self, owner: &'a str) -> AuthFuture<'a, bool> {
+        self.cache.knows_user(owner)
+    }
+
+    fn authorize<'a>(
+        &'a self,
+        owner: &'a str,
+        action: Action,
+        channel: &'a str,
+    ) -> AuthFuture<'a, Option<Rule>> {
+        self.cache.authorize(owner, action, channel)
+    }
+
+    fn quota<'a>(&'a self, owner: &'a str) -> AuthFuture<'a, Quota> {
+        self.cache.quota(owner)
+    }
+
+    fn networks<'a>(&'a self, owner: &'a str) -> AuthFuture<'a, Vec<NetworkRule>> {
+        self.cache.networks(owner)
+    }
+
+    fn reload(&self) -> AuthFuture<'_, Option<Vec<String>>> {
+        Box::pin(async move {
+            let modified = modified_at(&[&self.path]);
+            if *self.last_modified.lock().unwrap() == modified {
+                return Ok(None);
+            }
+            let auth_objects = self.load_auth_objects()?;
+            *self.last_modified.lock().unwrap() = modified;
+            Ok(self.cache.replace(auth_objects).await)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::time::Duration;
+
+    static AUTH_FILE: &str = r#"{
+        "users": {
+            "kitek": {
+                "secret": "first",
+                "secondary": {"secret": "second", "valid_from": 5, "valid_until": 10},
+                "roles": ["reader"],
+                "deny_sub": ["news.secret"],
+                "allow_pub": ["news.eu"]
+            }
+        },
+        "roles": {
+            "reader": {"allow_sub": ["news.*"]}
+        }
+    }"#;
+
+    fn write_auth_file(path: &str, contents: &str, modified: SystemTime) {
+        std::fs::write(path, contents).unwrap();
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("rust-feeds-{}-{}.json", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn parses_secondary_secret_and_role_grants() {
+        let path = temp_path("parse");
+        write_auth_file(&path, AUTH_FILE, SystemTime::now());
+        let store = FileAuthStore::open(&path).unwrap();
+        smol::block_on(async {
+            let credentials = store.credentials("kitek").await.unwrap();
+            assert_eq!(credentials.len(), 2);
+            assert!(credentials[0].slot == SecretSlot::Primary);
+            assert!(credentials[0].secret == StoredSecret::Plain("first".to_owned()));
+            assert!(credentials[1].slot == SecretSlot::Secondary);
+            assert!(credentials[1].secret == StoredSecret::Plain("second".to_owned()));
+            assert_eq!(credentials[1].valid_from, Some(5));
+            assert_eq!(credentials[1].valid_until, Some(10));
+
+            let role_rule = store.authorize("kitek", Action::Sub, "news.us").await;
+            let role_rule = role_rule.unwrap().unwrap();
+            assert!(role_rule.effect == Effect::Allow);
+            assert_eq!(role_rule.role.as_deref(), Some("reader"));
+            let denied = store.authorize("kitek", Action::Sub, "news.secret").await;
+            assert!(denied.unwrap().unwrap().effect == Effect::Deny);
+            let ungranted = store.authorize("kitek", Action::Pub, "news.us").await;
+            assert!(ungranted.unwrap().is_none());
+        });
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn failed_reload_is_retried() {
+        let path = temp_path("reload");
+        let opened_at = SystemTime::now() - Duration::from_secs(10);
+        write_auth_file(&path, AUTH_FILE, opened_at);
+        let store = FileAuthStore::open(&path).unwrap();
+
+        let modified = opened_at + Duration::from_secs(1);
+        write_auth_file(&path, r#"{"users": {"#, modified);
+        smol::block_on(async {
+            assert!(store.reload().await.is_err());
+            write_auth_file(&path, r#"{"users": {}}"#, modified);
+            let removed = store.reload().await.unwrap();
+            assert_eq!(removed, Some(vec!["kitek".to_owned()]));
+            assert!(store.reload().await.unwrap().is_none());
+        });
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,59 @@
+use crate::message_string::read_str_with_len;
+
+pub struct Headers<'a> {
+    entries: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> Headers<'a> {
+    pub fn get(&self, key: &str) -> Option<&'a str> {
+        self.entries
+            .iter()
+            .find(|(entry_key, _)| *entry_key == key)
+            .map(|(_, value)| *value)
+    }
+}
+
+pub fn read_headers(hdr_slice: &[u8]) -> Result<(usize, Headers<'_>), std::io::Error> {
+    if hdr_slice.len() < 2 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Data too short",
+        ));
+    }
+    let hdr_len = u16::from_be_bytes([hdr_slice[0], hdr_slice[1]]) as usize;
+    if hdr_slice.len() < 2 + hdr_len {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Headers too short",
+        ));
+    }
+
+    let hdr_data = &hdr_slice[2..2 + hdr_len];
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    while pos < hdr_data.len() {
+        let (key_len, key) = read_str_with_len(&hdr_data[pos..])?;
+        pos += 1 + key_len;
+        let (value_len, value) = read_str_with_len(&hdr_data[pos..])?;
+        pos += 1 + value_len;
+        entries.push((key, value));
+    }
+
+    Ok((2 + hdr_len, Headers { entries }))
+}
+
+pub fn write_headers(entries: &[(&str, &str)]) -> Vec<u8> {
+    let hdr_len: usize = entries
+        .iter()
+        .map(|(key, value)| 2 + key.len() + value.len())
+        .sum();
+    let mut data = Vec::with_capacity(2 + hdr_len);
+    data.extend_from_slice(&(hdr_len as u16).to_be_bytes());
+    for (key, value) in entries {
+        data.push(key.len() as u8);
+        data.extend_from_slice(key.as_bytes());
+        data.push(value.len() as u8);
+        data.extend_from_slice(value.as_bytes());
+    }
+    data
+}
@@ -0,0 +1,443 @@
+use crate::authstore::{Action, AuthBackend, AuthFuture, Effect, Rule};
+use crate::errors::AuthStoreError;
+use crate::quotas::Quota;
+use crate::secrets::{encode_hex, Credential};
+use serde::Deserialize;
+use serde_json::json;
+use smol::io::{AsyncReadExt, AsyncWriteExt};
+use smol::lock::RwLock;
+use smol::net::TcpStream;
+use smol::Timer;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+type CacheKey = (String, Action, String);
+
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+const MAX_RESPONSE_SIZE: u64 = 64 * 1024;
+
+#[derive(Deserialize)]
+struct CalloutResponse {
+    allow: bool,
+    ttl_secs: Option<u64>,
+}
+
+pub struct HttpAuthStore {
+    host: String,
+    path: String,
+    timeout: Duration,
+    cache_ttl: Duration,
+    cache: RwLock<HashMap<CacheKey, (bool, Instant)>>,
+}
+
+impl HttpAuthStore {
+    pub fn open(
+        url: &str,
+        timeout: Duration,
+        cache_ttl: Duration,
+    ) -> Result<HttpAuthStore, AuthStoreError> {
+        let Some(address) = url.strip_prefix("http://") else {
+            return Err(AuthStoreError::ConfigError(format!(
+                "Unsupported authorization callout url: {}",
+                url
+            )));
+        };
+        let (host, path) = match address.find('/') {
+            Some(i) => (&address[..i], &address[i..]),
+            None => (address, "/"),
+        };
+        if host.is_empty() {
+            return Err(AuthStoreError::ConfigError(format!(
+                "Missing host in authorization callout url: {}",
+                url
+            )));
+        }
+
+        Ok(HttpAuthStore {
+            host: host.to_owned(),
+            path: path.to_owned(),
+            timeout,
+            cache_ttl,
+            cache: RwLock::new(HashMap::new()),
+        })
+    }
+
+    async fn callout(&self, request: serde_json::Value) -> Result<CalloutResponse, AuthStoreError> {
+        smol::future::or(self.post(request.to_string()), async {
+            Timer::after(self.timeout).await;
+            Err(AuthStoreError::CalloutError(format!(
+                "no answer from {} within {} ms",
+                self.host,
+                self.timeout.as_millis()
+            )))
+        })
+        .await
+    }
+
+    async fn post(&self, body: String) -> Result<CalloutResponse, AuthStoreError> {
+        if body.len() > MAX_REQUEST_SIZE {
+            return Err(AuthStoreError::CalloutError(format!(
+                "request of {} bytes exceeds {} bytes",
+                body.len(),
+                MAX_REQUEST_SIZE
+            )));
+        }
+        let address = if self.host.contains(':') {
+            self.host.clone()
+        } else {
+            format!("{}:80", self.host)
+        };
+        let mut stream = TcpStream::connect(address).await?;
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            self.host,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await?;
+
+        let mut response = Vec::new();
+        (&mut stream)
+            .take(MAX_RESPONSE_SIZE + 1)
+            .read_to_end(&mut response)
+            .await?;
+        if response.len() as u64 > MAX_RESPONSE_SIZE {
+            return Err(AuthStoreError::CalloutError(format!(
+                "response exceeds {} bytes",
+                MAX_RESPONSE_SIZE
+            )));
+        }
+        parse_response(&response)
+    }
+}
+
+fn parse_response(response: &[u8]) -> Result<CalloutResponse, AuthStoreError> {
+    let malformed = || AuthStoreError::CalloutError("malformed HTTP response".to_owned());
+    let head_end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(malformed)?;
+    let head = String::from_utf8_lossy(&response[..head_end]);
+    let body = &response[head_end + 4..];
+
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|status_line| status_line.split_whitespace().nth(1))
+        .and_then(|status| status.parse::<u16>().ok());
+    let mut content_length = None;
+    let mut chunked = false;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(malformed());
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = Some(value.parse::<usize>().map_err(|_| malformed())?);
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            chunked = value.eq_ignore_ascii_case("chunked");
+        }
+    }
+
+    let body = match (chunked, content_length) {
+        (true, _) => decode_chunked(body)?,
+        (false, Some(length)) => body.get(..length).ok_or_else(malformed)?.to_vec(),
+        (false, None) => body.to_vec(),
+    };
+    match status {
+        Some(200..=299) => serde_json::from_slice(&body)
+            .map_err(|e| AuthStoreError::CalloutError(format!("invalid answer: {}", e))),
+        Some(status) => Err(AuthStoreError::CalloutError(format!(
+            "endpoint answered with status {}",
+            status
+        ))),
+        None => Err(AuthStoreError::CalloutError(
+            "malformed HTTP status line".to_owned(),
+        )),
+    }
+}
+
+fn decode_chunked(mut body: &[u8]) -> Result<Vec<u8>, AuthStoreError> {
+    let malformed = || AuthStoreError::CalloutError("malformed chunked body".to_owned());
+    let mut decoded = Vec::new();
+    loop {
+        let line_end = body
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .ok_or_else(malformed)?;
+        let size_line = std::str::from_utf8(&body[..line_end]).map_err(|_| malformed())?;
+        let size_hex = size_line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size_hex, 16).map_err(|_| malformed())?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Ok(decoded);
+        }
+        let chunk = body.get(..size).ok_or_else(malformed)?;
+        decoded.extend_from_slice(chunk);
+        body = body
+            .get(size..)
+            .and_then(|rest| rest.strip_prefix(b"\r\n"))
+            .ok_or_else(malformed)?;
+    }
+}
+
+impl AuthBackend for HttpAuthStore {
+    fn credentials<'a>(&'a self, _owner: &'a str) -> AuthFuture<'a, Vec<Credential>> {
+        Box::pin(async { Ok(Vec::new()) })
+    }
+
+    fn knows_user<'a>(&'a self, owner: &'a str) -> AuthFuture<'a, bool> {
+        Box::pin(async move {
+            let answer = self
+                .callout(json!({
+                    "action": "identity",
+                    "user": owner,
+                }))
+                .await?;
+            Ok(answer.allow)
+        })
+    }
+
+    fn authenticate<'a>(
+        &'a self,
+        owner: &'a str,
+        nonce: &'a [u8],
+        user_sha: &'a [u8],
+    ) -> AuthFuture<'a, bool> {
+        Box::pin(async move {
+            let answer = self
+                .callout(json!({
+                    "action": "auth",
+                    "user": owner,
+                    "nonce": String::from_utf8_lossy(nonce),
+                    "response": encode_hex(user_sha),
+                }))
+                .await?;
+            Ok(answer.allow)
+        })
+    }
+
+    fn authorize<'a>(
+        &'a self,
+        owner: &'a str,
+        action: Action,
+        channel: &'a str,
+    ) -> AuthFuture<'a, Option<Rule>> {
+        Box::pin(async move {
+            let key = (owner.to_owned(), action, channel.to_owned());
+            let cached = self
+                .cache
+                .read()
+                .await
+                .get(&key)
+                .filter(|(_, expires_at)| *expires_at > Instant::now())
+                .map(|(allow, _)| *allow);
+
+            let allow = match cached {
+                Some(allow) => allow,
+                None => {
+                    let answer = self
+                        .callout(json!({
+                            "action": match action {
+                                Action::Pub => "pub",
+                                Action::Sub => "sub",
+                            },
+                            "user": owner,
+                            "channel": channel,
+                        }))
+                        .await?;
+                    let ttl = answer
+                        .ttl_secs
+                        .map(Duration::from_secs)
+                        .unwrap_or(self.cache_ttl);
+                    let now = Instant::now();
+                    let mut cache = self.cache.write().await;
+                    cache.retain(|_, (_, expires_at)| *expires_at > now);
+                    cache.insert(key, (answer.allow, now + ttl));
+                    answer.allow
+                }
+            };
+
+            Ok(Some(Rule {
+                pattern: channel.to_owned(),
+                effect: if allow { Effect::Allow } else { Effect::Deny },
+                role: None,
+            }))
+        })
+    }
+
+    fn quota<'a>(&'a self, _owner: &'a str) -> AuthFuture<'a, Quota> {
+        Box::pin(async { Ok(Quota::default()) })
+    }
+
+    fn reload(&self) -> AuthFuture<'_, Option<Vec<String>>> {
+        Box::pin(async move {
+            let now = Instant::now();
+            self.cache
+                .write()
+                .await
+                .retain(|_, (_, expires_at)| *expires_at > now);
+            Ok(None)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smol::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    async fn stub(response: &'static str, delay: Duration) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/authorize", listener.local_addr().unwrap());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        smol::spawn(async move {
+            loop {
+                let Ok((mut stream, _)) = listener.accept().await else {
+                    return;
+                };
+                counter.fetch_add(1, Ordering::SeqCst);
+                smol::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    Timer::after(delay).await;
+                    let _ = stream.write_all(response.as_bytes()).await;
+                })
+                .detach();
+            }
+        })
+        .detach();
+        (url, calls)
+    }
+
+    fn store(url: &str) -> HttpAuthStore {
+        HttpAuthStore::open(url, Duration::from_millis(200), Duration::from_secs(60)).unwrap()
+    }
+
+    async fn effect(store: &HttpAuthStore) -> Result<Effect, AuthStoreError> {
+        let rule = store.authorize("kitek", Action::Sub, "news").await?;
+        Ok(rule.unwrap().effect)
+    }
+
+    #[test]
+    fn callout_allows_and_denies() {
+        smol::block_on(async {
+            let (url, _) = stub(
+                "HTTP/1.1 200 OK\r\nContent-Length: 14\r\n\r\n{\"allow\":true}",
+                Duration::ZERO,
+            )
+            .await;
+            assert!(matches!(effect(&store(&url)).await, Ok(Effect::Allow)));
+
+            let (url, _) = stub(
+                "HTTP/1.1 200 OK\r\nContent-Length: 15\r\n\r\n{\"allow\":false}",
+                Duration::ZERO,
+            )
+            .await;
+            assert!(matches!(effect(&store(&url)).await, Ok(Effect::Deny)));
+
+            let (url, _) = stub("HTTP/1.1 500 Oops\r\n\r\n", Duration::ZERO).await;
+            assert!(effect(&store(&url)).await.is_err());
+        });
+    }
+
+    #[test]
+    fn callout_answers_are_cached_for_ttl() {
+        smol::block_on(async {
+            let (url, calls) = stub(
+                "HTTP/1.1 200 OK\r\nContent-Length: 28\r\n\r\n{\"allow\":true,\"ttl_secs\":60}",
+                Duration::ZERO,
+            )
+            .await;
+            let cached = store(&url);
+            effect(&cached).await.unwrap();
+            effect(&cached).await.unwrap();
+            assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+            let (url, calls) = stub(
+                "HTTP/1.1 200 OK\r\nContent-Length: 27\r\n\r\n{\"allow\":true,\"ttl_secs\":0}",
+                Duration::ZERO,
+            )
+            .await;
+            let uncached = store(&url);
+            effect(&uncached).await.unwrap();
+            effect(&uncached).await.unwrap();
+            assert_eq!(calls.load(Ordering::SeqCst), 2);
+        });
+    }
+
+    #[test]
+    fn expired_answers_are_dropped_from_the_cache() {
+        smol::block_on(async {
+            let (url, _) = stub(
+                "HTTP/1.1 200 OK\r\nContent-Length: 27\r\n\r\n{\"allow\":true,\"ttl_secs\":0}",
+                Duration::ZERO,
+            )
+            .await;
+            let store = store(&url);
+            for channel in ["a", "b", "c"] {
+                store
+                    .authorize("kitek", Action::Sub, channel)
+                    .await
+                    .unwrap();
+            }
+            assert_eq!(store.cache.read().await.len(), 1);
+        });
+    }
+
+    #[test]
+    fn callout_times_out() {
+        smol::block_on(async {
+            let (url, _) = stub(
+                "HTTP/1.1 200 OK\r\nContent-Length: 14\r\n\r\n{\"allow\":true}",
+                Duration::from_secs(5),
+            )
+            .await;
+            let started = Instant::now();
+            assert!(effect(&store(&url)).await.is_err());
+            assert!(started.elapsed() < Duration::from_secs(2));
+        });
+    }
+
+    #[test]
+    fn callout_decodes_chunked_answers() {
+        smol::block_on(async {
+            let (url, _) = stub(
+                "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n9\r\n{\"allow\":\r\n5\r\ntrue}\r\n0\r\n\r\n",
+                Duration::ZERO,
+            )
+            .await;
+            assert!(matches!(effect(&store(&url)).await, Ok(Effect::Allow)));
+        });
+    }
+
+    #[test]
+    fn oversized_answers_are_rejected() {
+        let mut response = b"HTTP/1.1 200 OK\r\n\r\n".to_vec();
+        response.resize(MAX_RESPONSE_SIZE as usize + 1, b' ');
+        let response: &'static str = String::from_utf8(response).unwrap().leak();
+        smol::block_on(async {
+            let (url, _) = stub(response, Duration::ZERO).await;
+            assert!(effect(&store(&url)).await.is_err());
+        });
+        let long_owner = "x".repeat(MAX_REQUEST_SIZE);
+        smol::block_on(async {
+            let (url, calls) = stub(response, Duration::ZERO).await;
+            assert!(store(&url)
+                .authorize(&long_owner, Action::Sub, "news")
+                .await
+                .is_err());
+            assert_eq!(calls.load(Ordering::SeqCst), 0);
+        });
+    }
+}
@@ -0,0 +1,114 @@
+use crate::config::CONFIG;
+use crate::messaging::{broker_publish_frame, write_to_subscribers};
+use crate::scheduler::now_millis;
+use crate::subscription::OutboundMessage;
+use lazy_static::lazy_static;
+use serde_json::json;
+use smol::lock::Mutex;
+use smol::Timer;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum FailureKey {
+    Owner(String),
+    Ip(IpAddr),
+}
+
+impl std::fmt::Display for FailureKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FailureKey::Owner(owner) => write!(f, "user {}", owner),
+            FailureKey::Ip(ip) => write!(f, "address {}", ip),
+        }
+    }
+}
+
+#[derive(Default)]
+struct FailureRecord {
+    failures: u32,
+    last_failure: u64,
+    locked_until: u64,
+}
+
+static AUTH_FAILURES: AtomicU64 = AtomicU64::new(0);
+static LOCKOUTS: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref FAILURES: Mutex<HashMap<FailureKey, FailureRecord>> = Mutex::new(HashMap::new());
+}
+
+pub async fn locked_out_for(keys: &[FailureKey]) -> Option<u64> {
+    let now = now_millis();
+    let failures = FAILURES.lock().await;
+    keys.iter()
+        .filter_map(|key| failures.get(key))
+        .map(|record| record.locked_until.saturating_sub(now))
+        .filter(|remaining| *remaining > 0)
+        .max()
+}
+
+pub async fn record_failure(keys: &[FailureKey]) {
+    let now = now_millis();
+    let total = AUTH_FAILURES.fetch_add(1, Ordering::Relaxed) + 1;
+    let max_lockout = CONFIG.auth_lockout_max.as_millis() as u64;
+    let mut failures = FAILURES.lock().await;
+    failures.retain(|_, record| record.last_failure + max_lockout > now);
+
+    for key in keys {
+        let record = failures.entry(key.clone()).or_default();
+        record.failures += 1;
+        record.last_failure = now;
+        println!(
+            "Failed authentication for {} ({} consecutive, {} total)",
+            key, record.failures, total
+        );
+
+        if record.failures < CONFIG.auth_failure_threshold {
+            continue;
+        }
+        let exponent = (record.failures - CONFIG.auth_failure_threshold).min(16);
+        let lockout = (CONFIG.auth_lockout.as_millis() as u64)
+            .saturating_mul(1 << exponent)
+            .min(max_lockout);
+        record.locked_until = now + lockout;
+        let lockouts = LOCKOUTS.fetch_add(1, Ordering::Relaxed) + 1;
+        println!(
+            "Locked out {} for {} ms after {} failures ({} lockouts total)",
+            key, lockout, record.failures, lockouts
+        );
+    }
+}
+
+pub async fn record_success(owner: &str) {
+    FAILURES
+        .lock()
+        .await
+        .remove(&FailureKey::Owner(owner.to_owned()));
+}
+
+pub async fn lockout_stats() -> serde_json::Value {
+    let now = now_millis();
+    let failures = FAILURES.lock().await;
+    let locked_out = failures
+        .values()
+        .filter(|record| record.locked_until > now)
+        .count();
+    json!({
+        "auth_failures": AUTH_FAILURES.load(Ordering::Relaxed),
+        "lockouts": LOCKOUTS.load(Ordering::Relaxed),
+        "locked_out": locked_out,
+    })
+}
+
+pub async fn publish_lockout_stats(channel: String, interval: Duration) {
+    loop {
+        Timer::after(interval).await;
+        let payload = lockout_stats().await.to_string();
+        let frame = broker_publish_frame(&channel, payload.as_bytes());
+        write_to_subscribers(&channel, &[Arc::new(OutboundMessage::new(frame, None))]).await;
+    }
+}
@@ -1,22 +1,213 @@
-use authstore::AuthStoreSource;
-use server::Server;
+use audit::{feed_audit_log, parse_audit_filter, prune_audit_log, query_audit_log, run_audit_log};
+use authstore::{explain, open_auth_backend, Action};
+use channel_config::{feed_channel_config, CHANNEL_DB_PATH};
+use config::CONFIG;
+use expiry::publish_expired_stats;
+use lockout::publish_lockout_stats;
+use scheduler::{feed_scheduler, run_scheduler};
+use secrets::{
+    derive_scram_secret, derive_secret, parse_validity_window, SecretSlot, DEFAULT_ITERATIONS,
+};
+use server::{watch_auth, Server, DENIED_NETWORKS};
 use smol::Executor;
 use smol_macros::main;
 use sqlite_authstore::SqliteAuthStore;
 use std::sync::Arc;
+use tokens::TOKEN_KEYS;
+use transport::tls_acceptor;
+mod anonymous;
+mod audit;
 mod authstore;
+mod backpressure;
+mod channel_config;
+mod cidr;
+mod config;
+mod conflation;
+mod dead_letter;
+mod dedup;
 mod errors;
+mod expiry;
+mod file_authstore;
+mod headers;
+mod http_authstore;
+mod lockout;
+mod memory_authstore;
 mod message_string;
 mod messaging;
+mod migrations;
+mod quotas;
+mod scheduler;
+mod scram;
+mod secrets;
 mod server;
+mod snapshot;
 mod sqlite_authstore;
+mod subscription;
+mod tokens;
+mod transport;
 
 main! { async fn main() {
-    let auth_store = SqliteAuthStore {};
-    auth_store.feed_cache().await;
+    let args: Vec<String> = std::env::args().collect();
+    let scram = args.iter().any(|arg| arg == "--scram");
+    let args: Vec<&String> = args.iter().filter(|arg| *arg != "--scram").collect();
+    match args.get(1).map(|arg| arg.as_str()) {
+        Some("hash-secret") => {
+            match args.get(2) {
+                Some(secret) if scram => println!("{}", derive_scram_secret(secret, DEFAULT_ITERATIONS).encode()),
+                Some(secret) => println!("{}", derive_secret(secret, DEFAULT_ITERATIONS).encode()),
+                None => println!("Usage: rust-feeds hash-secret [--scram] <secret>"),
+            }
+            return;
+        }
+        Some("explain") => {
+            let action = match args.get(2).map(|arg| arg.as_str()) {
+                Some("pub") => Some(Action::Pub),
+                Some("sub") => Some(Action::Sub),
+                _ => None,
+            };
+            match (args.get(3), action, args.get(4)) {
+                (Some(owner), Some(action), Some(channel)) => {
+                    let explained = match open_auth_backend(&CONFIG) {
+                        Ok(auth) => explain(&*auth, owner, action, channel).await,
+                        Err(e) => Err(e),
+                    };
+                    match explained {
+                        Ok((allowed, reason)) => println!("{}: {}", if allowed { "ALLOW" } else { "DENY" }, reason),
+                        Err(e) => println!("Failed to explain permission: {}", e),
+                    }
+                }
+                _ => println!("Usage: rust-feeds explain <pub|sub> <owner> <channel>"),
+            }
+            return;
+        }
+        Some("audit") => {
+            let Some(path) = &CONFIG.audit_db else {
+                println!("RUST_FEEDS_AUDIT_DB is not set");
+                return;
+            };
+            let events = parse_audit_filter(&args[2..]).and_then(|filter| query_audit_log(path, &filter).map_err(|e| e.to_string()));
+            match events {
+                Ok(events) => {
+                    println!("timestamp\tsession\towner\tremote\taction\tchannel\toutcome");
+                    for event in events {
+                        println!("{}", event);
+                    }
+                }
+                Err(e) => println!("Usage: rust-feeds audit [--owner <owner>] [--action <action>] [--channel <channel>] [--since <ms>] [--until <ms>] [--limit <n>]\n{}", e),
+            }
+            return;
+        }
+        Some("migrate-secrets") => {
+            match SqliteAuthStore::open(&CONFIG.auth_db) {
+                Ok(auth_store) => match auth_store.migrate_secrets(scram) {
+                    Ok(migrated) => println!("Migrated {} secrets", migrated),
+                    Err(e) => println!("Failed to migrate secrets: {}", e),
+                },
+                Err(e) => println!("Failed to open auth database: {}", e),
+            }
+            return;
+        }
+        Some("set-secret") => {
+            let slot = args.get(3).and_then(|slot| SecretSlot::parse(slot));
+            match (args.get(2), slot, args.get(4)) {
+                (Some(owner), Some(slot), Some(secret)) => {
+                    let stored = parse_validity_window(&args[5..]).and_then(|(valid_from, valid_until)| {
+                        let auth_store = SqliteAuthStore::open(&CONFIG.auth_db).map_err(|e| e.to_string())?;
+                        auth_store.set_secret(owner, slot, secret, scram, valid_from, valid_until).map_err(|e| e.to_string())
+                    });
+                    match stored {
+                        Ok(true) => println!("Set {} secret of {}", slot.as_str(), owner),
+                        Ok(false) => println!("Unknown user {}", owner),
+                        Err(e) => println!("Failed to set secret: {}", e),
+                    }
+                }
+                _ => println!("Usage: rust-feeds set-secret [--scram] <owner> <primary|secondary> <secret> [--from <ms>] [--until <ms>]"),
+            }
+            return;
+        }
+        Some("promote-secret") => {
+            match args.get(2) {
+                Some(owner) => match SqliteAuthStore::open(&CONFIG.auth_db).map(|auth_store| auth_store.promote_secret(owner)) {
+                    Ok(Ok(true)) => println!("Promoted secondary secret of {}", owner),
+                    Ok(Ok(false)) => println!("No secondary secret for {}", owner),
+                    Ok(Err(e)) => println!("Failed to promote secret: {}", e),
+                    Err(e) => println!("Failed to open auth database: {}", e),
+                },
+                None => println!("Usage: rust-feeds promote-secret <owner>"),
+            }
+            return;
+        }
+        Some("retire-secret") => {
+            let slot = args.get(3).and_then(|slot| SecretSlot::parse(slot));
+            match (args.get(2), slot) {
+                (Some(owner), Some(slot)) => match SqliteAuthStore::open(&CONFIG.auth_db).map(|auth_store| auth_store.retire_secret(owner, slot)) {
+                    Ok(Ok(true)) => println!("Retired {} secret of {}", slot.as_str(), owner),
+                    Ok(Ok(false)) => println!("No {} secret for {}", slot.as_str(), owner),
+                    Ok(Err(e)) => println!("Failed to retire secret: {}", e),
+                    Err(e) => println!("Failed to open auth database: {}", e),
+                },
+                _ => println!("Usage: rust-feeds retire-secret <owner> <primary|secondary>"),
+            }
+            return;
+        }
+        Some("list-secrets") => {
+            match args.get(2) {
+                Some(owner) => match SqliteAuthStore::open(&CONFIG.auth_db).map(|auth_store| auth_store.list_secrets(owner)) {
+                    Ok(Ok(credentials)) => {
+                        println!("slot\tscheme\tvalid_from\tvalid_until");
+                        for credential in credentials {
+                            println!("{}", credential);
+                        }
+                    }
+                    Ok(Err(e)) => println!("Failed to list secrets: {}", e),
+                    Err(e) => println!("Failed to open auth database: {}", e),
+                },
+                None => println!("Usage: rust-feeds list-secrets <owner>"),
+            }
+            return;
+        }
+        _ => (),
+    }
+    let auth = match open_auth_backend(&CONFIG) {
+        Ok(auth) => auth,
+        Err(e) => {
+            println!("Failed to open auth backend: {}", e);
+            return;
+        }
+    };
+    let tls = match tls_acceptor(&CONFIG) {
+        Ok(tls) => tls,
+        Err(e) => {
+            println!("Failed to configure TLS: {}", e);
+            return;
+        }
+    };
+    lazy_static::initialize(&TOKEN_KEYS);
+    lazy_static::initialize(&DENIED_NETWORKS);
+    if let Err(e) = feed_channel_config(CHANNEL_DB_PATH).await {
+        println!("Failed to load channel config: {}", e);
+    }
+    if let Err(e) = feed_scheduler().await {
+        println!("Failed to load scheduled messages: {}", e);
+    }
+    if let Err(e) = feed_audit_log().await {
+        println!("Failed to open audit log: {}", e);
+    }
     println!("Starting app...");
     let executor = Arc::new(Executor::new());
-    if let Ok(server) = Server::new(2137).await {
+    executor.spawn(run_scheduler()).detach();
+    executor.spawn(run_audit_log()).detach();
+    if let Some(retention) = CONFIG.audit_retention {
+        executor.spawn(prune_audit_log(retention)).detach();
+    }
+    if let Some(channel) = &CONFIG.stats_channel {
+        executor.spawn(publish_lockout_stats(channel.clone(), CONFIG.stats_interval)).detach();
+        executor.spawn(publish_expired_stats(channel.clone(), CONFIG.stats_interval)).detach();
+    }
+    if let Some(interval) = CONFIG.auth_reload_interval {
+        executor.spawn(watch_auth(Arc::clone(&auth), interval)).detach();
+    }
+    if let Ok(server) = Server::new(2137, auth, tls).await {
         match server.listen(executor).await {
             Ok(_) => {return;}
             Err(_) => {
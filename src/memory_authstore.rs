@@ -0,0 +1,148 @@
+use crate::authstore::{
+    deciding_rule, Action, AuthBackend, AuthFuture, AuthObject, NetworkRule, Rule,
+};
+use crate::quotas::Quota;
+use crate::secrets::Credential;
+use smol::lock::RwLock;
+use std::collections::HashMap;
+
+pub struct MemoryAuthStore {
+    objects: RwLock<HashMap<String, AuthObject>>,
+}
+
+impl MemoryAuthStore {
+    pub fn new(objects: HashMap<String, AuthObject>) -> MemoryAuthStore {
+        MemoryAuthStore {
+            objects: RwLock::new(objects),
+        }
+    }
+
+    pub async fn replace(&self, auth_objects: HashMap<String, AuthObject>) -> Option<Vec<String>> {
+        let mut map = self.objects.write().await;
+        let added: Vec<&String> = auth_objects
+            .keys()
+            .filter(|owner| !map.contains_key(*owner))
+            .collect();
+        let changed: Vec<&String> = auth_objects
+            .iter()
+            .filter(|(owner, auth_object)| {
+                map.get(*owner).is_some_and(|cached| cached != *auth_object)
+            })
+            .map(|(owner, _)| owner)
+            .collect();
+        let removed: Vec<String> = map
+            .keys()
+            .filter(|owner| !auth_objects.contains_key(*owner))
+            .cloned()
+            .collect();
+
+        if added.is_empty() && changed.is_empty() && removed.is_empty() {
+            return None;
+        }
+        println!(
+            "Reloaded auth cache. Added: {:?}, changed: {:?}, removed: {:?}",
+            added, changed, removed
+        );
+        *map = auth_objects;
+        Some(removed)
+    }
+}
+
+impl AuthBackend for MemoryAuthStore {
+    fn credentials<'a>(&'a self, owner: &'a str) -> AuthFuture<'a, Vec<Credential>> {
+        Box::pin(async move {
+            let map = self.objects.read().await;
+            Ok(map
+                .get(owner)
+                .map(|auth_object| auth_object.secrets.clone())
+                .unwrap_or_default())
+        })
+    }
+
+    fn knows_user<'a>(&'a self, owner: &'a str) -> AuthFuture<'a, bool> {
+        Box::pin(async move { Ok(self.objects.read().await.contains_key(owner)) })
+    }
+
+    fn authorize<'a>(
+        &'a self,
+        owner: &'a str,
+        action: Action,
+        channel: &'a str,
+    ) -> AuthFuture<'a, Option<Rule>> {
+        Box::pin(async move {
+            let map = self.objects.read().await;
+            Ok(map
+                .get(owner)
+                .and_then(|auth_object| deciding_rule(auth_object.rules(action), channel))
+                .cloned())
+        })
+    }
+
+    fn quota<'a>(&'a self, owner: &'a str) -> AuthFuture<'a, Quota> {
+        Box::pin(async move {
+            let map = self.objects.read().await;
+            Ok(map
+                .get(owner)
+                .map(|auth_object| auth_object.quota.clone())
+                .unwrap_or_default())
+        })
+    }
+
+    fn networks<'a>(&'a self, owner: &'a str) -> AuthFuture<'a, Vec<NetworkRule>> {
+        Box::pin(async move {
+            let map = self.objects.read().await;
+            Ok(map
+                .get(owner)
+                .map(|auth_object| auth_object.networks.clone())
+                .unwrap_or_default())
+        })
+    }
+
+    fn reload(&self) -> AuthFuture<'_, Option<Vec<String>>> {
+        Box::pin(async { Ok(None) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authstore::Effect;
+
+    fn rule(pattern: &str, effect: Effect, role: Option<&str>) -> Rule {
+        Rule {
+            pattern: pattern.to_owned(),
+            effect,
+            role: role.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn deny_rules_override_allow_rules() {
+        let mut sub_rules = vec![
+            rule("news.*", Effect::Allow, Some("reader")),
+            rule("news.secret", Effect::Deny, None),
+            rule("news.eu", Effect::Allow, None),
+        ];
+        sub_rules.sort();
+        let store = MemoryAuthStore::new(HashMap::from([(
+            "kitek".to_owned(),
+            AuthObject {
+                secrets: Vec::new(),
+                sub_rules,
+                pub_rules: Vec::new(),
+                networks: Vec::new(),
+                quota: Quota::default(),
+            },
+        )]));
+        smol::block_on(async {
+            let denied = store.authorize("kitek", Action::Sub, "news.secret").await;
+            assert!(denied.unwrap() == Some(rule("news.secret", Effect::Deny, None)));
+            let allowed = store.authorize("kitek", Action::Sub, "news.us").await;
+            assert!(allowed.unwrap() == Some(rule("news.*", Effect::Allow, Some("reader"))));
+            let ungranted = store.authorize("kitek", Action::Pub, "news.us").await;
+            assert!(ungranted.unwrap().is_none());
+            let unknown = store.authorize("bob", Action::Sub, "news.us").await;
+            assert!(unknown.unwrap().is_none());
+        });
+    }
+}
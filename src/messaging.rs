@@ -1,17 +1,37 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
 use textnonce::TextNonce;
 
 use crate::{
-    authstore::{auth_pub, auth_sub},
-    errors::{AuthError, PublishError, SubscribeError},
+    anonymous::{anonymous_enabled, check_guest_rate, ANONYMOUS_OWNER},
+    audit::{audit, AuditPeer},
+    authstore::{auth_pub, auth_salt, auth_scram_keys, auth_sub, user_quota, AuthBackend},
+    backpressure::apply_backpressure,
+    channel_config::channel_config,
+    config::CONFIG,
+    conflation::conflation_key,
+    dead_letter::{dead_letter, DropReason},
+    dedup::{forget_message_id, is_duplicate},
+    errors::{AuthError, PublishError, QuotaError, SubscribeError},
+    expiry::{expire_message, expiry_time, is_expired},
+    headers::{read_headers, write_headers, Headers},
     message_string::{read_str_no_len, read_str_with_len},
-    server::{add_sub, BROKER_NAME, NAME_LENGTH, SUBS},
+    quotas::check_publish_quota,
+    scheduler::{
+        cancel_scheduled, delivery_time, list_scheduled, schedule_message, ScheduledMessage,
+    },
+    secrets::{fake_salt, DEFAULT_ITERATIONS},
+    server::{
+        add_snapshot_sub, add_sub, grant_sub_credit, remove_sub, subscription_count, BROKER_NAME,
+        NAME_LENGTH, SUBS,
+    },
+    snapshot::record_last_values,
+    subscription::OutboundMessage,
+    transport::{ClientReader, ClientStream, ClientWriter},
 };
 use smol::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncReadExt, AsyncWrite, AsyncWriteExt},
     lock::Mutex,
-    net::TcpStream,
 };
 
 #[repr(u8)]
@@ -22,6 +42,47 @@ enum OpCodes {
     Publish,
     Subscribe,
     Unsubscribe,
+    Ack,
+    PublishHeaders,
+    PublishBatch,
+    BatchAck,
+    ListScheduled,
+    CancelScheduled,
+    ScheduledList,
+    Credit,
+    Throttle,
+    SnapshotEnd,
+    SubscribeSnapshot,
+    SubscriptionRevoked,
+    SaltRequest,
+    Salt,
+    ScramClientFirst,
+    ScramServerFirst,
+    ScramClientFinal,
+    ScramServerFinal,
+    TokenAuth,
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy)]
+pub enum AckStatus {
+    Accepted,
+    Duplicate,
+    Unauthorized,
+    Scheduled,
+    Cancelled,
+    NotFound,
+    Expired,
+    Failed,
+}
+
+struct BatchEntry<'a> {
+    channel: &'a str,
+    headers: &'a [u8],
+    message_id: Option<&'a str>,
+    deliver_at: Option<u64>,
+    expires_at: Option<u64>,
+    payload: &'a [u8],
 }
 
 impl TryFrom<u8> for OpCodes {
@@ -35,12 +96,31 @@ impl TryFrom<u8> for OpCodes {
             3 => Ok(Self::Publish),
             4 => Ok(Self::Subscribe),
             5 => Ok(Self::Unsubscribe),
+            6 => Ok(Self::Ack),
+            7 => Ok(Self::PublishHeaders),
+            8 => Ok(Self::PublishBatch),
+            9 => Ok(Self::BatchAck),
+            10 => Ok(Self::ListScheduled),
+            11 => Ok(Self::CancelScheduled),
+            12 => Ok(Self::ScheduledList),
+            13 => Ok(Self::Credit),
+            14 => Ok(Self::Throttle),
+            15 => Ok(Self::SnapshotEnd),
+            16 => Ok(Self::SubscribeSnapshot),
+            17 => Ok(Self::SubscriptionRevoked),
+            18 => Ok(Self::SaltRequest),
+            19 => Ok(Self::Salt),
+            20 => Ok(Self::ScramClientFirst),
+            21 => Ok(Self::ScramServerFirst),
+            22 => Ok(Self::ScramClientFinal),
+            23 => Ok(Self::ScramServerFinal),
+            24 => Ok(Self::TokenAuth),
             _ => Err(()),
         }
     }
 }
 #[inline(always)]
-pub async fn write_info_message(stream: &mut TcpStream) -> Result<TextNonce, std::io::Error> {
+pub async fn write_info_message(stream: &mut ClientStream) -> Result<TextNonce, std::io::Error> {
     let nonce = TextNonce::new();
     let total_len = 6 + 32 + NAME_LENGTH as usize;
     let total_len_32 = total_len as u32;
@@ -48,19 +128,69 @@ pub async fn write_info_message(stream: &mut TcpStream) -> Result<TextNonce, std
     data.extend_from_slice(&total_len_32.to_be_bytes());
     data.push(OpCodes::Info as u8);
     data.push(NAME_LENGTH);
-    data.extend_from_slice(&BROKER_NAME.as_bytes());
-    data.extend_from_slice(&nonce.as_bytes());
+    data.extend_from_slice(BROKER_NAME.as_bytes());
+    data.extend_from_slice(nonce.as_bytes());
 
     stream.write_all(&data).await?;
 
-    return Ok(nonce);
+    Ok(nonce)
 }
 
 #[inline(always)]
-pub async fn read_auth_message(stream: &mut TcpStream) -> Result<Vec<u8>, std::io::Error> {
+pub async fn read_auth_message(
+    stream: &mut ClientStream,
+    auth: &dyn AuthBackend,
+) -> Result<Vec<u8>, std::io::Error> {
     let mut auth_buf = [0u8; 4];
     stream.read_exact(&mut auth_buf).await?;
+    let mut len = u32::from_be_bytes(auth_buf);
+
+    while len >= 6 {
+        check_frame_size(stream, len).await?;
+        let mut data_buf = vec![0u8; (len - 4) as usize];
+        stream.read_exact(&mut data_buf).await?;
+        if data_buf[0] != OpCodes::SaltRequest as u8 {
+            return finish_auth_message(stream, auth_buf, data_buf).await;
+        }
+
+        let (_, owner_name) = read_str_with_len(&data_buf[1..])?;
+        let (iterations, salt) = auth_salt(auth, owner_name).await;
+        write_salt_message(stream, iterations, &salt).await?;
+
+        stream.read_exact(&mut auth_buf).await?;
+        len = u32::from_be_bytes(auth_buf);
+    }
+
+    write_error_message(
+        stream,
+        &format!("Expected at least 39 bytes. Got: {}.", len),
+    )
+    .await?;
+    Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        "Data length less than excepted",
+    ))
+}
+
+async fn finish_auth_message(
+    stream: &mut ClientStream,
+    auth_buf: [u8; 4],
+    data_buf: Vec<u8>,
+) -> Result<Vec<u8>, std::io::Error> {
     let len = u32::from_be_bytes(auth_buf);
+    if data_buf[0] == OpCodes::ScramClientFirst as u8 || data_buf[0] == OpCodes::TokenAuth as u8 {
+        let mut data = Vec::with_capacity(len as usize);
+        data.extend_from_slice(&auth_buf);
+        data.extend_from_slice(&data_buf);
+        return Ok(data);
+    }
+
+    let mut data = Vec::with_capacity(len as usize);
+    data.extend_from_slice(&auth_buf);
+    data.extend_from_slice(&data_buf);
+    if anonymous_enabled() && is_anonymous_request(&data) {
+        return Ok(data);
+    }
 
     if len < 39 {
         write_error_message(
@@ -74,13 +204,10 @@ pub async fn read_auth_message(stream: &mut TcpStream) -> Result<Vec<u8>, std::i
         ));
     }
 
-    let mut data_buf = vec![0u8; (len - 4) as usize];
-    stream.read_exact(&mut data_buf).await?;
-
     if data_buf[0] != OpCodes::Auth as u8 {
         write_error_message(
             stream,
-            &format!("Invalid Error Code. Expected 2. Got: {}.", auth_buf[4]),
+            &format!("Invalid Error Code. Expected 2. Got: {}.", data_buf[0]),
         )
         .await?;
         return Err(std::io::Error::new(
@@ -89,25 +216,216 @@ pub async fn read_auth_message(stream: &mut TcpStream) -> Result<Vec<u8>, std::i
         ));
     }
 
-    let mut data = Vec::with_capacity(len as usize);
-    data.extend_from_slice(&auth_buf);
-    data.extend_from_slice(&data_buf);
+    Ok(data)
+}
+
+pub fn is_scram_request(auth_data: &[u8]) -> bool {
+    auth_data[4] == OpCodes::ScramClientFirst as u8
+}
+
+pub fn is_token_request(auth_data: &[u8]) -> bool {
+    auth_data[4] == OpCodes::TokenAuth as u8
+}
+
+pub fn is_anonymous_request(auth_data: &[u8]) -> bool {
+    auth_data[4] == OpCodes::Auth as u8
+        && read_str_with_len(&auth_data[5..]).is_ok_and(|(name_len, owner_name)| {
+            owner_name == ANONYMOUS_OWNER && auth_data.len() == 6 + name_len
+        })
+}
+
+fn check_frame_owner(peer: &AuditPeer, data: &[u8]) -> Result<(), String> {
+    let owned = [
+        OpCodes::Publish,
+        OpCodes::Subscribe,
+        OpCodes::Unsubscribe,
+        OpCodes::PublishHeaders,
+        OpCodes::PublishBatch,
+        OpCodes::ListScheduled,
+        OpCodes::CancelScheduled,
+        OpCodes::Credit,
+        OpCodes::SubscribeSnapshot,
+    ]
+    .into_iter()
+    .any(|op_code| data[4] == op_code as u8);
+    if !owned {
+        return Ok(());
+    }
+    let owner_matches = data.len() > 5
+        && read_str_with_len(&data[5..])
+            .is_ok_and(|(_, owner_name)| peer.owner.as_deref() == Some(owner_name));
+    if !owner_matches {
+        return Err("Frame owner does not match the authenticated user".to_owned());
+    }
+    Ok(())
+}
+
+async fn check_guest_frame(peer: &AuditPeer, data: &[u8]) -> Result<(), String> {
+    let allowed = [
+        OpCodes::Subscribe,
+        OpCodes::SubscribeSnapshot,
+        OpCodes::Unsubscribe,
+        OpCodes::Credit,
+    ]
+    .into_iter()
+    .any(|op_code| data[4] == op_code as u8);
+    if !allowed {
+        return Err("Anonymous sessions may only subscribe to public channels".to_owned());
+    }
+    if let Some(session_id) = peer.session_id {
+        check_guest_rate(session_id)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+pub async fn authenticate_scram(
+    stream: &mut ClientStream,
+    auth: &dyn AuthBackend,
+    server_nonce: &[u8],
+    client_first: &[u8],
+) -> Result<Option<(String, [u8; 32])>, std::io::Error> {
+    let client_first_body = &client_first[5..];
+    let (name_len, owner_name) = read_str_with_len(client_first_body)?;
+    let client_nonce = &client_first_body[1 + name_len..];
+    if client_nonce.is_empty() || client_nonce.len() + server_nonce.len() > u8::MAX as usize {
+        write_error_message(stream, "Invalid client nonce").await?;
+        return Ok(None);
+    }
+
+    let keys = auth_scram_keys(auth, owner_name).await;
+    let (iterations, salt) = match keys.first() {
+        Some(keys) => (keys.iterations, keys.salt.clone()),
+        None => (DEFAULT_ITERATIONS, fake_salt(owner_name)),
+    };
+    let mut nonce = client_nonce.to_vec();
+    nonce.extend_from_slice(server_nonce);
+
+    let mut server_first_body = Vec::with_capacity(5 + salt.len() + nonce.len());
+    server_first_body.extend_from_slice(&iterations.to_be_bytes());
+    server_first_body.push(salt.len() as u8);
+    server_first_body.extend_from_slice(&salt);
+    server_first_body.extend_from_slice(&nonce);
+    stream
+        .write_all(&scram_frame(OpCodes::ScramServerFirst, &server_first_body))
+        .await?;
+
+    let mut len_buf = [0u8; 4];
+    stream.read_exact(&mut len_buf).await?;
+    let len = u32::from_be_bytes(len_buf);
+    if len < 6 {
+        write_error_message(stream, "Invalid SCRAM client final message").await?;
+        return Ok(None);
+    }
+    check_frame_size(stream, len).await?;
+    let mut client_final = vec![0u8; (len - 4) as usize];
+    stream.read_exact(&mut client_final).await?;
+    if client_final[0] != OpCodes::ScramClientFinal as u8 {
+        write_error_message(
+            stream,
+            &format!(
+                "Invalid Error Code. Expected {}. Got: {}.",
+                OpCodes::ScramClientFinal as u8,
+                client_final[0]
+            ),
+        )
+        .await?;
+        return Ok(None);
+    }
+
+    let client_final_body = &client_final[1..];
+    let proof_shift = 1 + client_final_body[0] as usize;
+    if client_final_body.len() != proof_shift + 32 || client_final_body[1..proof_shift] != nonce[..]
+    {
+        write_error_message(stream, "Invalid SCRAM client final message").await?;
+        return Ok(None);
+    }
 
-    return Ok(data);
+    let mut auth_message =
+        Vec::with_capacity(client_first_body.len() + server_first_body.len() + proof_shift);
+    auth_message.extend_from_slice(client_first_body);
+    auth_message.extend_from_slice(&server_first_body);
+    auth_message.extend_from_slice(&client_final_body[..proof_shift]);
+
+    let verified = keys.iter().find(|keys| {
+        keys.iterations == iterations
+            && keys.salt == salt
+            && keys.verify_client_proof(&auth_message, &client_final_body[proof_shift..])
+    });
+    Ok(verified.map(|keys| (owner_name.to_owned(), keys.server_signature(&auth_message))))
+}
+
+pub async fn write_scram_server_final(
+    stream: &mut ClientStream,
+    signature: &[u8],
+) -> Result<(), std::io::Error> {
+    stream
+        .write_all(&scram_frame(OpCodes::ScramServerFinal, signature))
+        .await
+}
+
+async fn check_frame_size(
+    stream: &mut (impl AsyncWrite + Unpin),
+    len: u32,
+) -> Result<(), std::io::Error> {
+    if len <= CONFIG.max_frame_size {
+        return Ok(());
+    }
+    let limit_error = QuotaError::MessageSize(len as u64, CONFIG.max_frame_size as u64);
+    write_error_message(stream, &limit_error.to_string()).await?;
+    Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        limit_error.to_string(),
+    ))
+}
+
+fn scram_frame(op_code: OpCodes, body: &[u8]) -> Vec<u8> {
+    let capacity = 5 + body.len();
+    let mut data: Vec<u8> = Vec::with_capacity(capacity);
+    data.extend_from_slice(&(capacity as u32).to_be_bytes());
+    data.push(op_code as u8);
+    data.extend_from_slice(body);
+    data
 }
 
 #[inline(always)]
 pub async fn read_arbitrary_message(
-    stream_reader: &mut TcpStream,
-    stream_writer: &Arc<Mutex<TcpStream>>,
+    stream_reader: &mut ClientReader,
+    stream_writer: &Arc<Mutex<ClientWriter>>,
     read_length: u32,
+    auth: &dyn AuthBackend,
+    peer: &AuditPeer,
 ) -> Result<(), std::io::Error> {
+    if read_length < 5 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Data too short",
+        ));
+    }
+    if read_length > CONFIG.max_frame_size {
+        let mut sw = stream_writer.lock().await;
+        check_frame_size(&mut *sw, read_length).await?;
+    }
     let mut buff = vec![0u8; (read_length - 4) as usize];
     stream_reader.read_exact(&mut buff).await?;
     let mut data_buff = Vec::with_capacity(read_length as usize);
     data_buff.extend_from_slice(&read_length.to_be_bytes());
     data_buff.extend_from_slice(&buff);
 
+    if let Err(message) = check_frame_owner(peer, &data_buff) {
+        audit(peer, "frame_owner", None, &message);
+        let mut sw = stream_writer.lock().await;
+        return write_error_message(&mut *sw, &message).await;
+    }
+    if peer.guest {
+        if let Err(message) = check_guest_frame(peer, &data_buff).await {
+            audit(peer, "guest_request", None, &message);
+            let mut sw = stream_writer.lock().await;
+            return write_error_message(&mut *sw, &message).await;
+        }
+    }
+
     match data_buff[4].try_into() {
         Ok(OpCodes::ErrorCode) => {
             let mut sw = stream_writer.lock().await;
@@ -121,13 +439,145 @@ pub async fn read_arbitrary_message(
             let mut sw = stream_writer.lock().await;
             wrong_op_code_response(&mut sw, OpCodes::Auth).await?
         }
-        Ok(OpCodes::Publish) => {
-            if let Err(e) = publish_message(&data_buff).await {
-                match e {
+        Ok(OpCodes::Ack) => {
+            let mut sw = stream_writer.lock().await;
+            wrong_op_code_response(&mut sw, OpCodes::Ack).await?
+        }
+        Ok(OpCodes::BatchAck) => {
+            let mut sw = stream_writer.lock().await;
+            wrong_op_code_response(&mut sw, OpCodes::BatchAck).await?
+        }
+        Ok(OpCodes::ScheduledList) => {
+            let mut sw = stream_writer.lock().await;
+            wrong_op_code_response(&mut sw, OpCodes::ScheduledList).await?
+        }
+        Ok(OpCodes::Throttle) => {
+            let mut sw = stream_writer.lock().await;
+            wrong_op_code_response(&mut sw, OpCodes::Throttle).await?
+        }
+        Ok(OpCodes::SnapshotEnd) => {
+            let mut sw = stream_writer.lock().await;
+            wrong_op_code_response(&mut sw, OpCodes::SnapshotEnd).await?
+        }
+        Ok(OpCodes::SubscriptionRevoked) => {
+            let mut sw = stream_writer.lock().await;
+            wrong_op_code_response(&mut sw, OpCodes::SubscriptionRevoked).await?
+        }
+        Ok(OpCodes::SaltRequest) => {
+            let mut sw = stream_writer.lock().await;
+            wrong_op_code_response(&mut sw, OpCodes::SaltRequest).await?
+        }
+        Ok(OpCodes::Salt) => {
+            let mut sw = stream_writer.lock().await;
+            wrong_op_code_response(&mut sw, OpCodes::Salt).await?
+        }
+        Ok(OpCodes::ScramClientFirst) => {
+            let mut sw = stream_writer.lock().await;
+            wrong_op_code_response(&mut sw, OpCodes::ScramClientFirst).await?
+        }
+        Ok(OpCodes::ScramServerFirst) => {
+            let mut sw = stream_writer.lock().await;
+            wrong_op_code_response(&mut sw, OpCodes::ScramServerFirst).await?
+        }
+        Ok(OpCodes::ScramClientFinal) => {
+            let mut sw = stream_writer.lock().await;
+            wrong_op_code_response(&mut sw, OpCodes::ScramClientFinal).await?
+        }
+        Ok(OpCodes::ScramServerFinal) => {
+            let mut sw = stream_writer.lock().await;
+            wrong_op_code_response(&mut sw, OpCodes::ScramServerFinal).await?
+        }
+        Ok(OpCodes::TokenAuth) => {
+            let mut sw = stream_writer.lock().await;
+            wrong_op_code_response(&mut sw, OpCodes::TokenAuth).await?
+        }
+        Ok(OpCodes::SubscribeSnapshot) => {
+            match process_subscribe_message(auth, peer, &data_buff).await {
+                Err(e) => {
+                    let mut sw = stream_writer.lock().await;
+                    match e {
+                        SubscribeError::IoError(io_error) => {
+                            write_error_message(&mut *sw, &io_error.to_string()).await?
+                        }
+                        SubscribeError::QuotaError(quota_error) => {
+                            audit(peer, "subscribe", None, &quota_error.to_string());
+                            write_error_message(&mut *sw, &quota_error.to_string()).await?
+                        }
+                        SubscribeError::AuthError(
+                            AuthError::UnauthSub(channel) | AuthError::UnauthPub(channel),
+                        ) => audit(peer, "subscribe", Some(&channel), "denied"),
+                    }
+                }
+                Ok(channel_name) => {
+                    audit(peer, "subscribe", Some(channel_name), "allowed");
+                    if channel_config(channel_name).await.snapshot {
+                        add_snapshot_sub(channel_name, peer, stream_writer.clone()).await
+                    } else {
+                        let mut sw = stream_writer.lock().await;
+                        write_error_message(
+                            &mut *sw,
+                            &format!("Channel has no snapshot: {}", channel_name),
+                        )
+                        .await?;
+                    }
+                }
+            }
+        }
+        Ok(OpCodes::ListScheduled) => {
+            let owner_name = peer.owner.as_deref().unwrap_or_default();
+            let scheduled = list_scheduled(owner_name).await;
+            let mut sw = stream_writer.lock().await;
+            write_scheduled_list_message(&mut sw, &scheduled).await?;
+        }
+        Ok(OpCodes::CancelScheduled) => {
+            let owner_name = peer.owner.as_deref().unwrap_or_default();
+            let (name_len, _) = read_str_with_len(&data_buff[5..])?;
+            let (_, message_id) = read_str_no_len(&data_buff[6 + name_len..])?;
+            let status = if cancel_scheduled(owner_name, message_id).await {
+                AckStatus::Cancelled
+            } else {
+                AckStatus::NotFound
+            };
+            let mut sw = stream_writer.lock().await;
+            write_ack_message(&mut sw, status, message_id).await?;
+        }
+        Ok(OpCodes::PublishBatch) => match publish_batch(auth, peer, &data_buff).await {
+            Ok((channels, statuses)) => {
+                let mut ack = Some(batch_ack_frame(&statuses));
+                for channel in channels {
+                    if ack.is_none() {
+                        break;
+                    }
+                    ack = apply_backpressure(channel, stream_writer, ack).await;
+                }
+                if let Some(ack) = ack {
+                    let mut sw = stream_writer.lock().await;
+                    sw.write_all(&ack).await?;
+                }
+            }
+            Err(e) => {
+                if let PublishError::QuotaError(quota_error) = &e {
+                    audit(peer, "publish", None, &quota_error.to_string());
+                }
+                let mut sw = stream_writer.lock().await;
+                write_error_message(&mut *sw, &e.to_string()).await?;
+            }
+        },
+        Ok(OpCodes::Publish) | Ok(OpCodes::PublishHeaders) => {
+            match publish_message(auth, peer, &data_buff).await {
+                Ok((channel_name, ack)) => {
+                    let ack = ack.map(|(message_id, status)| ack_frame(status, &message_id));
+                    if let Some(ack) = apply_backpressure(channel_name, stream_writer, ack).await {
+                        let mut sw = stream_writer.lock().await;
+                        sw.write_all(&ack).await?;
+                    }
+                }
+                Err(e) => match e {
                     PublishError::AuthError(AuthError::UnauthPub(channel)) => {
+                        audit(peer, "publish", Some(&channel), "denied");
                         let mut sw = stream_writer.lock().await;
                         write_error_message(
-                            &mut sw,
+                            &mut *sw,
                             &format!(
                                 "User is not allowed to send to channel: {}",
                                 channel.to_owned()
@@ -137,31 +587,76 @@ pub async fn read_arbitrary_message(
                     }
                     PublishError::IoError(err) => {
                         let mut sw = stream_writer.lock().await;
-                        write_error_message(&mut sw, &err.to_string()).await?;
+                        write_error_message(&mut *sw, &err.to_string()).await?;
+                    }
+                    PublishError::QuotaError(err) => {
+                        audit(peer, "publish", None, &err.to_string());
+                        let mut sw = stream_writer.lock().await;
+                        write_error_message(&mut *sw, &err.to_string()).await?;
                     }
                     _ => (),
-                }
-            };
+                },
+            }
         }
-        Ok(OpCodes::Subscribe) => match process_subscribe_message(&data_buff).await {
+        Ok(OpCodes::Subscribe) => match process_subscribe_message(auth, peer, &data_buff).await {
             Err(e) => {
                 let mut sw = stream_writer.lock().await;
                 match e {
                     SubscribeError::IoError(io_error) => {
-                        write_error_message(&mut sw, &io_error.to_string()).await?;
+                        write_error_message(&mut *sw, &io_error.to_string()).await?
                     }
-                    _ => (),
+                    SubscribeError::QuotaError(quota_error) => {
+                        audit(peer, "subscribe", None, &quota_error.to_string());
+                        write_error_message(&mut *sw, &quota_error.to_string()).await?
+                    }
+                    SubscribeError::AuthError(
+                        AuthError::UnauthSub(channel) | AuthError::UnauthPub(channel),
+                    ) => audit(peer, "subscribe", Some(&channel), "denied"),
+                }
+            }
+            Ok(channel_name) => {
+                audit(peer, "subscribe", Some(channel_name), "allowed");
+                add_sub(channel_name, peer, stream_writer.clone()).await
+            }
+        },
+        Ok(OpCodes::Credit) => match process_credit_message(auth, peer, &data_buff).await {
+            Err(e) => {
+                let mut sw = stream_writer.lock().await;
+                match e {
+                    SubscribeError::IoError(io_error) => {
+                        write_error_message(&mut *sw, &io_error.to_string()).await?
+                    }
+                    SubscribeError::QuotaError(quota_error) => {
+                        audit(peer, "subscribe", None, &quota_error.to_string());
+                        write_error_message(&mut *sw, &quota_error.to_string()).await?
+                    }
+                    SubscribeError::AuthError(
+                        AuthError::UnauthSub(channel) | AuthError::UnauthPub(channel),
+                    ) => audit(peer, "subscribe", Some(&channel), "denied"),
                 }
             }
-            Ok((owner_name, channel_name)) => {
-                add_sub(channel_name, owner_name, stream_writer.clone()).await
+            Ok((channel_name, credit)) => {
+                grant_sub_credit(channel_name, peer, stream_writer.clone(), credit).await
             }
         },
-        Ok(OpCodes::Unsubscribe) => todo!(),
+        Ok(OpCodes::Unsubscribe) => {
+            let (name_len, _) = read_str_with_len(&data_buff[5..])?;
+            let (_, channel_name) = read_str_no_len(&data_buff[6 + name_len..])?;
+            if remove_sub(channel_name, peer).await {
+                audit(peer, "unsubscribe", Some(channel_name), "removed");
+            } else {
+                let mut sw = stream_writer.lock().await;
+                write_error_message(
+                    &mut *sw,
+                    &format!("Not subscribed to channel: {}", channel_name),
+                )
+                .await?;
+            }
+        }
         Err(_) => {
             let mut sw = stream_writer.lock().await;
             write_error_message(
-                &mut sw,
+                &mut *sw,
                 &format!("Unknown OpCode provided. Got: {}", data_buff[4]),
             )
             .await?;
@@ -172,7 +667,11 @@ pub async fn read_arbitrary_message(
 }
 
 #[inline(always)]
-async fn publish_message(data: &[u8]) -> Result<(), PublishError> {
+async fn publish_message<'a>(
+    auth: &dyn AuthBackend,
+    peer: &AuditPeer,
+    data: &'a [u8],
+) -> Result<(&'a str, Option<(String, AckStatus)>), PublishError> {
     if data.len() < 6 {
         return Err(PublishError::IoError(std::io::Error::new(
             std::io::ErrorKind::InvalidData,
@@ -180,53 +679,488 @@ async fn publish_message(data: &[u8]) -> Result<(), PublishError> {
         )));
     }
     let (name_len, owner_name) = read_str_with_len(&data[5..])?;
-    let (_, channel_name) = read_str_with_len(&data[6 + name_len..])?;
+    let (chan_len, channel_name) = read_str_with_len(&data[6 + name_len..])?;
 
-    if !auth_pub(owner_name, channel_name).await {
+    if !auth_pub(auth, peer, channel_name).await {
         return Err(PublishError::AuthError(AuthError::UnauthPub(
             channel_name.to_owned(),
         )));
     }
-    push_publish_data_to_streams(channel_name, &data).await?;
+    let (_, payload) = split_publish_frame(data)?;
+    check_publish_quota(owner_name, &user_quota(auth, peer).await, &[payload.len()]).await?;
 
-    Ok(())
+    if data[4] != OpCodes::PublishHeaders as u8 {
+        push_publish_data_to_streams(
+            channel_name,
+            vec![OutboundMessage::new(data.to_vec(), None)],
+        )
+        .await?;
+        return Ok((channel_name, None));
+    }
+
+    let (_, headers) = read_headers(&data[7 + name_len + chan_len..])?;
+    let message_id = headers.get("id");
+    let expires_at = expiry_time(&headers)?;
+    let deliver_at = delivery_time(&headers)?;
+    if let Some(message_id) = message_id {
+        let config = channel_config(channel_name).await;
+        if is_duplicate(channel_name, message_id, &config).await {
+            return Ok((
+                channel_name,
+                Some((message_id.to_owned(), AckStatus::Duplicate)),
+            ));
+        }
+    }
+
+    if is_expired(expires_at) {
+        expire_message(channel_name, data).await;
+        return Ok((
+            channel_name,
+            message_id.map(|message_id| (message_id.to_owned(), AckStatus::Expired)),
+        ));
+    }
+
+    if let Some(deliver_at) = deliver_at {
+        let scheduled_id = message_id
+            .map(str::to_owned)
+            .unwrap_or_else(|| TextNonce::new().into_string());
+        let message = ScheduledMessage {
+            owner: owner_name.to_owned(),
+            channel: channel_name.to_owned(),
+            deliver_at,
+            expires_at,
+            frame: data.to_vec(),
+        };
+        let status = schedule_frame(&scheduled_id, message).await;
+        if let (Some(message_id), AckStatus::Failed) = (message_id, status) {
+            forget_message_id(channel_name, message_id).await;
+        }
+        return Ok((channel_name, Some((scheduled_id, status))));
+    }
+    let pushed = push_publish_data_to_streams(
+        channel_name,
+        vec![OutboundMessage::new(data.to_vec(), expires_at)],
+    )
+    .await;
+    if let (Some(message_id), Err(_)) = (message_id, &pushed) {
+        forget_message_id(channel_name, message_id).await;
+    }
+    pushed?;
+
+    Ok((
+        channel_name,
+        message_id.map(|message_id| (message_id.to_owned(), AckStatus::Accepted)),
+    ))
+}
+
+async fn schedule_frame(scheduled_id: &str, message: ScheduledMessage) -> AckStatus {
+    match schedule_message(scheduled_id, message).await {
+        Ok(true) => AckStatus::Scheduled,
+        Ok(false) => AckStatus::Duplicate,
+        Err(_) => AckStatus::Failed,
+    }
+}
+
+fn read_batch_entries(data: &[u8]) -> Result<Vec<BatchEntry<'_>>, std::io::Error> {
+    if data.len() < 2 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Data too short",
+        ));
+    }
+    let entry_count = u16::from_be_bytes([data[0], data[1]]) as usize;
+    let mut entries = Vec::with_capacity(entry_count);
+    let mut pos = 2;
+    for _ in 0..entry_count {
+        let (chan_len, channel) = read_str_with_len(&data[pos..])?;
+        pos += 1 + chan_len;
+        let (hdr_len, headers) = read_headers(&data[pos..])?;
+        let message_id = headers.get("id");
+        let deliver_at = delivery_time(&headers)?;
+        let expires_at = expiry_time(&headers)?;
+        let headers = &data[pos..pos + hdr_len];
+        pos += hdr_len;
+        if data.len() < pos + 4 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Data too short",
+            ));
+        }
+        let payload_len =
+            u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+        pos += 4;
+        if data.len() < pos + payload_len {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Payload too short",
+            ));
+        }
+        entries.push(BatchEntry {
+            channel,
+            headers,
+            message_id,
+            deliver_at,
+            expires_at,
+            payload: &data[pos..pos + payload_len],
+        });
+        pos += payload_len;
+    }
+
+    Ok(entries)
 }
 
 #[inline(always)]
-async fn process_subscribe_message(data: &[u8]) -> Result<(&str, &str), SubscribeError> {
+async fn publish_batch<'a>(
+    auth: &dyn AuthBackend,
+    peer: &AuditPeer,
+    data: &'a [u8],
+) -> Result<(Vec<&'a str>, Vec<AckStatus>), PublishError> {
     if data.len() < 6 {
-        return Err(SubscribeError::IoError(std::io::Error::new(
+        return Err(PublishError::IoError(std::io::Error::new(
             std::io::ErrorKind::InvalidData,
             "Data too short",
         )));
     }
     let (name_len, owner_name) = read_str_with_len(&data[5..])?;
+    let entries = read_batch_entries(&data[6 + name_len..])?;
+    let payload_sizes: Vec<usize> = entries.iter().map(|entry| entry.payload.len()).collect();
+    check_publish_quota(owner_name, &user_quota(auth, peer).await, &payload_sizes).await?;
+
+    let mut allowed: HashMap<&str, bool> = HashMap::new();
+    let mut channel_messages: Vec<(&str, Vec<OutboundMessage>)> = Vec::new();
+    let mut accepted_ids: Vec<(&str, &str)> = Vec::new();
+    let mut statuses = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let is_allowed = match allowed.get(entry.channel) {
+            Some(is_allowed) => *is_allowed,
+            None => {
+                let is_allowed = auth_pub(auth, peer, entry.channel).await;
+                if !is_allowed {
+                    audit(peer, "publish", Some(entry.channel), "denied");
+                }
+                allowed.insert(entry.channel, is_allowed);
+                is_allowed
+            }
+        };
+        if !is_allowed {
+            statuses.push(AckStatus::Unauthorized);
+            continue;
+        }
+
+        if let Some(message_id) = entry.message_id {
+            let config = channel_config(entry.channel).await;
+            if is_duplicate(entry.channel, message_id, &config).await {
+                statuses.push(AckStatus::Duplicate);
+                continue;
+            }
+        }
+
+        if is_expired(entry.expires_at) {
+            expire_message(entry.channel, &publish_frame(owner_name, &entry)).await;
+            statuses.push(AckStatus::Expired);
+            continue;
+        }
+
+        if let Some(deliver_at) = entry.deliver_at {
+            let scheduled_id = entry
+                .message_id
+                .map(str::to_owned)
+                .unwrap_or_else(|| TextNonce::new().into_string());
+            let message = ScheduledMessage {
+                owner: owner_name.to_owned(),
+                channel: entry.channel.to_owned(),
+                deliver_at,
+                expires_at: entry.expires_at,
+                frame: publish_frame(owner_name, &entry),
+            };
+            let status = schedule_frame(&scheduled_id, message).await;
+            if let (Some(message_id), AckStatus::Failed) = (entry.message_id, status) {
+                forget_message_id(entry.channel, message_id).await;
+            }
+            statuses.push(status);
+            continue;
+        }
+
+        let messages = match channel_messages
+            .iter_mut()
+            .find(|(channel, _)| *channel == entry.channel)
+        {
+            Some((_, messages)) => messages,
+            None => {
+                channel_messages.push((entry.channel, Vec::new()));
+                &mut channel_messages.last_mut().unwrap().1
+            }
+        };
+        messages.push(OutboundMessage::new(
+            publish_frame(owner_name, &entry),
+            entry.expires_at,
+        ));
+        if let Some(message_id) = entry.message_id {
+            accepted_ids.push((entry.channel, message_id));
+        }
+        statuses.push(AckStatus::Accepted);
+    }
+
+    let mut channels = Vec::with_capacity(channel_messages.len());
+    for (channel, messages) in channel_messages {
+        if let Err(e) = push_publish_data_to_streams(channel, messages).await {
+            for (accepted, message_id) in accepted_ids
+                .iter()
+                .filter(|(accepted, _)| !channels.contains(accepted))
+            {
+                forget_message_id(accepted, message_id).await;
+            }
+            return Err(e.into());
+        }
+        channels.push(channel);
+    }
+
+    Ok((channels, statuses))
+}
+
+fn publish_frame(owner_name: &str, entry: &BatchEntry) -> Vec<u8> {
+    let has_headers = entry.headers.len() > 2;
+    let mut total_len = 7 + owner_name.len() + entry.channel.len() + entry.payload.len();
+    if has_headers {
+        total_len += entry.headers.len();
+    }
+
+    let mut frames = Vec::with_capacity(total_len);
+    frames.extend_from_slice(&(total_len as u32).to_be_bytes());
+    if has_headers {
+        frames.push(OpCodes::PublishHeaders as u8);
+    } else {
+        frames.push(OpCodes::Publish as u8);
+    }
+    frames.push(owner_name.len() as u8);
+    frames.extend_from_slice(owner_name.as_bytes());
+    frames.push(entry.channel.len() as u8);
+    frames.extend_from_slice(entry.channel.as_bytes());
+    if has_headers {
+        frames.extend_from_slice(entry.headers);
+    }
+    frames.extend_from_slice(entry.payload);
+    frames
+}
+
+#[inline(always)]
+async fn process_subscribe_message<'a>(
+    auth: &dyn AuthBackend,
+    peer: &AuditPeer,
+    data: &'a [u8],
+) -> Result<&'a str, SubscribeError> {
+    if data.len() < 6 {
+        return Err(SubscribeError::IoError(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Data too short",
+        )));
+    }
+    let (name_len, _) = read_str_with_len(&data[5..])?;
     let (_, channel_name) = read_str_no_len(&data[6 + name_len..])?;
 
-    if !auth_sub(owner_name, channel_name).await {
+    if !auth_sub(auth, peer, channel_name).await {
         return Err(SubscribeError::AuthError(AuthError::UnauthSub(
             channel_name.to_owned(),
         )));
     }
+    check_subscription_quota(auth, peer, channel_name).await?;
 
-    Ok((owner_name, channel_name))
+    Ok(channel_name)
 }
 
 #[inline(always)]
-async fn push_publish_data_to_streams(channel: &str, data: &[u8]) -> Result<(), std::io::Error> {
-    let subs_map = SUBS.read().await;
-    if let Some(subs_vec) = subs_map.get(channel) {
-        let fut = subs_vec.iter().map(|(_, stream_mutex)| async {
-            let mut stream = stream_mutex.lock().await;
-            stream.write_all(data).await
-        });
-        futures::future::join_all(fut).await;
+async fn process_credit_message<'a>(
+    auth: &dyn AuthBackend,
+    peer: &AuditPeer,
+    data: &'a [u8],
+) -> Result<(&'a str, u64), SubscribeError> {
+    if data.len() < 6 {
+        return Err(SubscribeError::IoError(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Data too short",
+        )));
     }
+    let (name_len, _) = read_str_with_len(&data[5..])?;
+    let credit_pos = 6 + name_len;
+    if data.len() < credit_pos + 4 {
+        return Err(SubscribeError::IoError(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Data too short",
+        )));
+    }
+    let credit = u32::from_be_bytes([
+        data[credit_pos],
+        data[credit_pos + 1],
+        data[credit_pos + 2],
+        data[credit_pos + 3],
+    ]);
+    let (_, channel_name) = read_str_no_len(&data[credit_pos + 4..])?;
+
+    if !auth_sub(auth, peer, channel_name).await {
+        return Err(SubscribeError::AuthError(AuthError::UnauthSub(
+            channel_name.to_owned(),
+        )));
+    }
+    check_subscription_quota(auth, peer, channel_name).await?;
+
+    Ok((channel_name, credit as u64))
+}
+
+async fn check_subscription_quota(
+    auth: &dyn AuthBackend,
+    peer: &AuditPeer,
+    channel_name: &str,
+) -> Result<(), QuotaError> {
+    let Some(max_subscriptions) = user_quota(auth, peer).await.max_subscriptions else {
+        return Ok(());
+    };
+    if subscription_count(peer, channel_name).await as u64 >= max_subscriptions {
+        return Err(QuotaError::SubscriptionLimit(max_subscriptions));
+    }
+    Ok(())
+}
+
+#[inline(always)]
+pub async fn push_publish_data_to_streams(
+    channel: &str,
+    messages: Vec<OutboundMessage>,
+) -> Result<(), std::io::Error> {
+    let config = channel_config(channel).await;
+    let messages: Vec<Arc<OutboundMessage>> = messages
+        .into_iter()
+        .map(|mut message| {
+            message.key = conflation_key(&config, &message.frame);
+            Arc::new(message)
+        })
+        .collect();
+
+    if !write_to_subscribers(channel, &messages).await {
+        for message in &messages {
+            dead_letter(channel, DropReason::NoSubscribers, &message.frame).await;
+        }
+    }
+    Ok(())
+}
+
+pub async fn write_to_subscribers(channel: &str, messages: &[Arc<OutboundMessage>]) -> bool {
+    let Some(overflow) = deliver_to_subscribers(channel, messages).await else {
+        return false;
+    };
+    for message in overflow {
+        dead_letter(channel, DropReason::SlowConsumer, &message.frame).await;
+    }
+    true
+}
+
+pub async fn deliver_to_subscribers(
+    channel: &str,
+    messages: &[Arc<OutboundMessage>],
+) -> Option<Vec<Arc<OutboundMessage>>> {
+    let subs_map = SUBS.read().await;
+    record_last_values(channel, messages).await;
+    let subs_vec = subs_map
+        .get(channel)
+        .filter(|subs_vec| !subs_vec.is_empty())?;
+    let fut = subs_vec
+        .values()
+        .map(|subscription| subscription.deliver(messages));
+    Some(futures::future::join_all(fut).await.concat())
+}
+
+pub fn snapshot_end_frame(channel: &str) -> Vec<u8> {
+    let capacity = 6 + channel.len();
+    let mut data: Vec<u8> = Vec::with_capacity(capacity);
+    data.extend_from_slice(&(capacity as u32).to_be_bytes());
+    data.push(OpCodes::SnapshotEnd as u8);
+    data.push(channel.len() as u8);
+    data.extend_from_slice(channel.as_bytes());
+    data
+}
+
+pub async fn write_salt_message(
+    stream: &mut ClientStream,
+    iterations: u32,
+    salt: &[u8],
+) -> Result<(), std::io::Error> {
+    let capacity = 10 + salt.len();
+    let mut data: Vec<u8> = Vec::with_capacity(capacity);
+    data.extend_from_slice(&(capacity as u32).to_be_bytes());
+    data.push(OpCodes::Salt as u8);
+    data.extend_from_slice(&iterations.to_be_bytes());
+    data.push(salt.len() as u8);
+    data.extend_from_slice(salt);
+
+    stream.write_all(&data).await?;
     Ok(())
 }
 
+pub async fn write_subscription_revoked_message(
+    stream: &mut ClientWriter,
+    channel: &str,
+) -> Result<(), std::io::Error> {
+    let capacity = 6 + channel.len();
+    let mut data: Vec<u8> = Vec::with_capacity(capacity);
+    data.extend_from_slice(&(capacity as u32).to_be_bytes());
+    data.push(OpCodes::SubscriptionRevoked as u8);
+    data.push(channel.len() as u8);
+    data.extend_from_slice(channel.as_bytes());
+
+    stream.write_all(&data).await?;
+    Ok(())
+}
+
+pub fn split_publish_frame(frame: &[u8]) -> Result<(Option<Headers<'_>>, &[u8]), std::io::Error> {
+    if frame.len() < 6 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Data too short",
+        ));
+    }
+    let (name_len, _) = read_str_with_len(&frame[5..])?;
+    let (chan_len, _) = read_str_with_len(&frame[6 + name_len..])?;
+    let body = &frame[7 + name_len + chan_len..];
+    if frame[4] != OpCodes::PublishHeaders as u8 {
+        return Ok((None, body));
+    }
+    let (hdr_len, headers) = read_headers(body)?;
+    Ok((Some(headers), &body[hdr_len..]))
+}
+
+pub fn dropped_message_frame(
+    target: &str,
+    channel: &str,
+    reason: DropReason,
+    frame: &[u8],
+) -> Vec<u8> {
+    let headers = write_headers(&[("reason", reason.as_str()), ("channel", channel)]);
+    let total_len = 7 + NAME_LENGTH as usize + target.len() + headers.len() + frame.len();
+    let mut data: Vec<u8> = Vec::with_capacity(total_len);
+    data.extend_from_slice(&(total_len as u32).to_be_bytes());
+    data.push(OpCodes::PublishHeaders as u8);
+    data.push(NAME_LENGTH);
+    data.extend_from_slice(BROKER_NAME.as_bytes());
+    data.push(target.len() as u8);
+    data.extend_from_slice(target.as_bytes());
+    data.extend_from_slice(&headers);
+    data.extend_from_slice(frame);
+    data
+}
+
+pub fn broker_publish_frame(channel: &str, payload: &[u8]) -> Vec<u8> {
+    let total_len = 7 + NAME_LENGTH as usize + channel.len() + payload.len();
+    let mut data: Vec<u8> = Vec::with_capacity(total_len);
+    data.extend_from_slice(&(total_len as u32).to_be_bytes());
+    data.push(OpCodes::Publish as u8);
+    data.push(NAME_LENGTH);
+    data.extend_from_slice(BROKER_NAME.as_bytes());
+    data.push(channel.len() as u8);
+    data.extend_from_slice(channel.as_bytes());
+    data.extend_from_slice(payload);
+    data
+}
+
 async fn wrong_op_code_response(
-    stream_writer: &mut TcpStream,
+    stream_writer: &mut ClientWriter,
     op_code: OpCodes,
 ) -> Result<(), std::io::Error> {
     write_error_message(
@@ -243,15 +1177,80 @@ async fn wrong_op_code_response(
     ))
 }
 
+pub async fn write_ack_message(
+    stream: &mut ClientWriter,
+    status: AckStatus,
+    message_id: &str,
+) -> Result<(), std::io::Error> {
+    stream.write_all(&ack_frame(status, message_id)).await?;
+    Ok(())
+}
+
+pub fn ack_frame(status: AckStatus, message_id: &str) -> Vec<u8> {
+    let capacity = 7 + message_id.len();
+    let mut data: Vec<u8> = Vec::with_capacity(capacity);
+    data.extend_from_slice(&(capacity as u32).to_be_bytes());
+    data.push(OpCodes::Ack as u8);
+    data.push(status as u8);
+    data.push(message_id.len() as u8);
+    data.extend_from_slice(message_id.as_bytes());
+    data
+}
+
+pub fn batch_ack_frame(statuses: &[AckStatus]) -> Vec<u8> {
+    let capacity = 7 + statuses.len();
+    let mut data: Vec<u8> = Vec::with_capacity(capacity);
+    data.extend_from_slice(&(capacity as u32).to_be_bytes());
+    data.push(OpCodes::BatchAck as u8);
+    data.extend_from_slice(&(statuses.len() as u16).to_be_bytes());
+    data.extend(statuses.iter().map(|status| *status as u8));
+    data
+}
+
+pub fn throttle_frame(channel: &str, throttled: bool) -> Vec<u8> {
+    let capacity = 7 + channel.len();
+    let mut data: Vec<u8> = Vec::with_capacity(capacity);
+    data.extend_from_slice(&(capacity as u32).to_be_bytes());
+    data.push(OpCodes::Throttle as u8);
+    data.push(throttled as u8);
+    data.push(channel.len() as u8);
+    data.extend_from_slice(channel.as_bytes());
+    data
+}
+
+pub async fn write_scheduled_list_message(
+    stream: &mut ClientWriter,
+    scheduled: &[(String, String, u64)],
+) -> Result<(), std::io::Error> {
+    let capacity = 7 + scheduled
+        .iter()
+        .map(|(id, channel, _)| 10 + id.len() + channel.len())
+        .sum::<usize>();
+    let mut data: Vec<u8> = Vec::with_capacity(capacity);
+    data.extend_from_slice(&(capacity as u32).to_be_bytes());
+    data.push(OpCodes::ScheduledList as u8);
+    data.extend_from_slice(&(scheduled.len() as u16).to_be_bytes());
+    for (id, channel, deliver_at) in scheduled {
+        data.push(id.len() as u8);
+        data.extend_from_slice(id.as_bytes());
+        data.push(channel.len() as u8);
+        data.extend_from_slice(channel.as_bytes());
+        data.extend_from_slice(&deliver_at.to_be_bytes());
+    }
+
+    stream.write_all(&data).await?;
+    Ok(())
+}
+
 pub async fn write_error_message(
-    stream: &mut TcpStream,
+    stream: &mut (impl AsyncWrite + Unpin),
     error_message: &str,
 ) -> Result<(), std::io::Error> {
     let capacity = 5 + error_message.len();
     let mut data: Vec<u8> = Vec::with_capacity(capacity);
-    data.extend_from_slice(&capacity.to_be_bytes());
+    data.extend_from_slice(&(capacity as u32).to_be_bytes());
     data.push(OpCodes::ErrorCode as u8);
-    data.extend_from_slice(&error_message.as_bytes());
+    data.extend_from_slice(error_message.as_bytes());
 
     stream.write_all(&data).await?;
     Ok(())
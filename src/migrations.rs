@@ -0,0 +1,22 @@
+use rusqlite::{Connection, Transaction};
+
+pub type Migration = fn(&Transaction) -> Result<(), rusqlite::Error>;
+
+pub fn run_migrations(
+    conn: &mut Connection,
+    migrations: &[Migration],
+) -> Result<(), rusqlite::Error> {
+    let version: usize = conn.query_row("PRAGMA user_version;", [], |row| row.get(0))?;
+    for (index, migration) in migrations.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        migration(&tx)?;
+        tx.pragma_update(None, "user_version", index + 1)?;
+        tx.commit()?;
+        println!(
+            "Applied migration {} to {}",
+            index + 1,
+            conn.path().unwrap_or("database")
+        );
+    }
+    Ok(())
+}
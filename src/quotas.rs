@@ -0,0 +1,70 @@
+use crate::errors::QuotaError;
+use crate::scheduler::now_millis;
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use smol::lock::Mutex;
+use std::collections::HashMap;
+
+#[derive(Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Quota {
+    pub max_connections: Option<u64>,
+    pub max_subscriptions: Option<u64>,
+    pub max_msgs_per_sec: Option<u64>,
+    pub max_bytes_per_sec: Option<u64>,
+    pub max_message_size: Option<u64>,
+}
+
+#[derive(Default)]
+struct RateWindow {
+    started_at: u64,
+    messages: u64,
+    bytes: u64,
+}
+
+lazy_static! {
+    static ref RATES: Mutex<HashMap<String, RateWindow>> = Mutex::new(HashMap::new());
+}
+
+pub async fn check_publish_quota(
+    owner: &str,
+    quota: &Quota,
+    payload_sizes: &[usize],
+) -> Result<(), QuotaError> {
+    if let Some(max_size) = quota.max_message_size {
+        if let Some(size) = payload_sizes.iter().map(|size| *size as u64).max() {
+            if size > max_size {
+                return Err(QuotaError::MessageSize(size, max_size));
+            }
+        }
+    }
+    if quota.max_msgs_per_sec.is_none() && quota.max_bytes_per_sec.is_none() {
+        return Ok(());
+    }
+
+    let now = now_millis();
+    let messages = payload_sizes.len() as u64;
+    let bytes: u64 = payload_sizes.iter().map(|size| *size as u64).sum();
+    let mut rates = RATES.lock().await;
+    let window = rates.entry(owner.to_owned()).or_default();
+    if now >= window.started_at + 1000 {
+        *window = RateWindow {
+            started_at: now,
+            ..Default::default()
+        };
+    }
+
+    if let Some(max_msgs) = quota.max_msgs_per_sec {
+        if window.messages + messages > max_msgs {
+            return Err(QuotaError::MessageRate(max_msgs));
+        }
+    }
+    if let Some(max_bytes) = quota.max_bytes_per_sec {
+        if window.bytes + bytes > max_bytes {
+            return Err(QuotaError::ByteRate(max_bytes));
+        }
+    }
+    window.messages += messages;
+    window.bytes += bytes;
+    Ok(())
+}
@@ -0,0 +1,265 @@
+use crate::config::CONFIG;
+use crate::expiry::{expire_message, is_expired};
+use crate::headers::Headers;
+use crate::messaging::push_publish_data_to_streams;
+use crate::subscription::OutboundMessage;
+use lazy_static::lazy_static;
+use rusqlite::{params, Connection};
+use smol::channel::{unbounded, Receiver, Sender};
+use smol::lock::Mutex;
+use smol::Timer;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+static CREATE_TABLE: &str = "CREATE TABLE IF NOT EXISTS scheduled_messages (
+             id TEXT PRIMARY KEY,
+             owner TEXT NOT NULL,
+             channel TEXT NOT NULL,
+             deliver_at INTEGER NOT NULL,
+             expires_at INTEGER,
+             frame BLOB NOT NULL
+         );";
+
+static QUERY: &str = "SELECT id, owner, channel, deliver_at, expires_at, frame
+             FROM scheduled_messages;";
+
+pub struct ScheduledMessage {
+    pub owner: String,
+    pub channel: String,
+    pub deliver_at: u64,
+    pub expires_at: Option<u64>,
+    pub frame: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Scheduler {
+    due: BTreeSet<(u64, String)>,
+    messages: HashMap<String, ScheduledMessage>,
+    pending: HashSet<String>,
+}
+
+lazy_static! {
+    static ref SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler::default());
+    static ref STORE: std::sync::Mutex<Option<Connection>> = std::sync::Mutex::new(None);
+    static ref WAKEUP: (Sender<()>, Receiver<()>) = unbounded();
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+pub fn delivery_time(headers: &Headers) -> Result<Option<u64>, std::io::Error> {
+    let deliver_at = match (headers.get("deliver_at"), headers.get("delay")) {
+        (Some(deliver_at), _) => deliver_at.parse::<u64>(),
+        (None, Some(delay)) => delay
+            .parse::<u64>()
+            .map(|delay| now_millis().saturating_add(delay)),
+        (None, None) => return Ok(None),
+    };
+    match deliver_at {
+        Ok(deliver_at) if deliver_at > now_millis() => Ok(Some(deliver_at)),
+        Ok(_) => Ok(None),
+        Err(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Invalid delivery time",
+        )),
+    }
+}
+
+pub async fn feed_scheduler() -> Result<(), rusqlite::Error> {
+    let Some(path) = &CONFIG.schedule_db else {
+        return Ok(());
+    };
+    let conn = Connection::open(path)?;
+    conn.execute(CREATE_TABLE, [])?;
+
+    let mut scheduler = SCHEDULER.lock().await;
+    {
+        let mut stmt = conn.prepare(QUERY)?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                ScheduledMessage {
+                    owner: row.get(1)?,
+                    channel: row.get(2)?,
+                    deliver_at: row.get::<_, i64>(3)? as u64,
+                    expires_at: row
+                        .get::<_, Option<i64>>(4)?
+                        .map(|expires_at| expires_at as u64),
+                    frame: row.get(5)?,
+                },
+            ))
+        })?;
+        for row in rows {
+            let (id, message) = row?;
+            scheduler.due.insert((message.deliver_at, id.clone()));
+            scheduler.messages.insert(id, message);
+        }
+    }
+    println!("Loaded {} scheduled messages", scheduler.messages.len());
+    *STORE.lock().unwrap() = Some(conn);
+    Ok(())
+}
+
+async fn persist_message(
+    id: String,
+    message: ScheduledMessage,
+) -> (String, ScheduledMessage, Result<(), rusqlite::Error>) {
+    smol::unblock(move || {
+        let result = match &*STORE.lock().unwrap() {
+            Some(store) => store
+                .execute(
+                    "INSERT INTO scheduled_messages (id, owner, channel, deliver_at, expires_at, frame)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6);",
+                    params![
+                        id,
+                        message.owner,
+                        message.channel,
+                        message.deliver_at as i64,
+                        message.expires_at.map(|expires_at| expires_at as i64),
+                        message.frame
+                    ],
+                )
+                .map(|_| ()),
+            None => Ok(()),
+        };
+        (id, message, result)
+    })
+    .await
+}
+
+async fn forget_messages(ids: Vec<String>) {
+    if ids.is_empty() {
+        return;
+    }
+    smol::unblock(move || {
+        if let Some(store) = &*STORE.lock().unwrap() {
+            for id in ids {
+                if let Err(e) =
+                    store.execute("DELETE FROM scheduled_messages WHERE id = ?1;", [&id])
+                {
+                    println!("Failed to remove scheduled message {}: {}", id, e);
+                }
+            }
+        }
+    })
+    .await
+}
+
+pub async fn schedule_message(
+    id: &str,
+    message: ScheduledMessage,
+) -> Result<bool, rusqlite::Error> {
+    {
+        let mut scheduler = SCHEDULER.lock().await;
+        if scheduler.messages.contains_key(id) || !scheduler.pending.insert(id.to_owned()) {
+            return Ok(false);
+        }
+    }
+
+    let (id, message, persisted) = persist_message(id.to_owned(), message).await;
+    let mut scheduler = SCHEDULER.lock().await;
+    scheduler.pending.remove(&id);
+    if let Err(e) = persisted {
+        println!("Failed to persist scheduled message {}: {}", id, e);
+        return Err(e);
+    }
+    scheduler.due.insert((message.deliver_at, id.clone()));
+    scheduler.messages.insert(id, message);
+    let _ = WAKEUP.0.try_send(());
+    Ok(true)
+}
+
+pub async fn cancel_scheduled(owner: &str, id: &str) -> bool {
+    {
+        let mut scheduler = SCHEDULER.lock().await;
+        match scheduler.messages.get(id) {
+            Some(message) if message.owner == owner => {
+                let deliver_at = message.deliver_at;
+                scheduler.remove(deliver_at, id);
+            }
+            _ => return false,
+        }
+    }
+    forget_messages(vec![id.to_owned()]).await;
+    true
+}
+
+pub async fn list_scheduled(owner: &str) -> Vec<(String, String, u64)> {
+    let scheduler = SCHEDULER.lock().await;
+    scheduler
+        .due
+        .iter()
+        .filter_map(|(deliver_at, id)| {
+            let message = scheduler.messages.get(id)?;
+            if message.owner != owner {
+                return None;
+            }
+            Some((id.clone(), message.channel.clone(), *deliver_at))
+        })
+        .collect()
+}
+
+impl Scheduler {
+    fn remove(&mut self, deliver_at: u64, id: &str) -> Option<ScheduledMessage> {
+        self.due.remove(&(deliver_at, id.to_owned()));
+        self.messages.remove(id)
+    }
+
+    fn take_due(&mut self, now: u64) -> Vec<(String, ScheduledMessage)> {
+        let mut due = Vec::new();
+        while let Some((deliver_at, id)) = self.due.first().cloned() {
+            if deliver_at > now {
+                break;
+            }
+            if let Some(message) = self.remove(deliver_at, &id) {
+                due.push((id, message));
+            }
+        }
+        due
+    }
+}
+
+pub async fn run_scheduler() {
+    loop {
+        let (due, next) = {
+            let mut scheduler = SCHEDULER.lock().await;
+            let due = scheduler.take_due(now_millis());
+            let next = scheduler.due.first().map(|(deliver_at, _)| *deliver_at);
+            (due, next)
+        };
+
+        let (ids, due): (Vec<String>, Vec<ScheduledMessage>) = due.into_iter().unzip();
+        forget_messages(ids).await;
+        for message in due {
+            if is_expired(message.expires_at) {
+                expire_message(&message.channel, &message.frame).await;
+                continue;
+            }
+            let outbound = OutboundMessage::new(message.frame, message.expires_at);
+            if let Err(e) = push_publish_data_to_streams(&message.channel, vec![outbound]).await {
+                println!(
+                    "Failed to deliver scheduled message on {}: {}",
+                    message.channel, e
+                );
+            }
+        }
+
+        let wait = match next {
+            Some(deliver_at) => Duration::from_millis(deliver_at.saturating_sub(now_millis())),
+            None => Duration::from_secs(60),
+        };
+        smol::future::or(
+            async {
+                Timer::after(wait).await;
+            },
+            async {
+                let _ = WAKEUP.1.recv().await;
+            },
+        )
+        .await;
+    }
+}
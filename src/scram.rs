@@ -0,0 +1,46 @@
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+#[derive(Clone, PartialEq)]
+pub struct ScramKeys {
+    pub iterations: u32,
+    pub salt: Vec<u8>,
+    pub stored_key: [u8; 32],
+    pub server_key: [u8; 32],
+}
+
+impl ScramKeys {
+    pub fn from_salted(iterations: u32, salt: &[u8], salted_secret: &[u8]) -> ScramKeys {
+        let client_key = hmac_sha256(salted_secret, b"Client Key");
+        ScramKeys {
+            iterations,
+            salt: salt.to_vec(),
+            stored_key: Sha256::digest(client_key).into(),
+            server_key: hmac_sha256(salted_secret, b"Server Key"),
+        }
+    }
+
+    pub fn verify_client_proof(&self, auth_message: &[u8], proof: &[u8]) -> bool {
+        if proof.len() != 32 {
+            return false;
+        }
+        let client_signature = hmac_sha256(&self.stored_key, auth_message);
+        let client_key: Vec<u8> = proof
+            .iter()
+            .zip(client_signature)
+            .map(|(p, s)| p ^ s)
+            .collect();
+        Sha256::digest(client_key).ct_eq(&self.stored_key).into()
+    }
+
+    pub fn server_signature(&self, auth_message: &[u8]) -> [u8; 32] {
+        hmac_sha256(&self.server_key, auth_message)
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
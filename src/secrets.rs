@@ -0,0 +1,265 @@
+use crate::scram::ScramKeys;
+use lazy_static::lazy_static;
+use pbkdf2::pbkdf2_hmac;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use textnonce::TextNonce;
+
+pub static SECRET_SCHEME: &str = "pbkdf2-sha256";
+pub static SCRAM_SCHEME: &str = "scram-sha-256";
+pub static DEFAULT_ITERATIONS: u32 = 100_000;
+
+#[derive(Clone, PartialEq)]
+pub enum StoredSecret {
+    Plain(String),
+    Derived {
+        iterations: u32,
+        salt: Vec<u8>,
+        key: [u8; 32],
+    },
+    Scram(ScramKeys),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SecretSlot {
+    Primary,
+    Secondary,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct Credential {
+    pub slot: SecretSlot,
+    pub secret: StoredSecret,
+    pub valid_from: Option<u64>,
+    pub valid_until: Option<u64>,
+}
+
+lazy_static! {
+    static ref FAKE_SALT_KEY: Vec<u8> = random_bytes();
+}
+
+impl StoredSecret {
+    pub fn parse(secret: &str) -> Result<StoredSecret, String> {
+        if let Some(encoded) = secret.strip_prefix(SCRAM_SCHEME) {
+            return parse_scram(encoded);
+        }
+        let Some(encoded) = secret.strip_prefix(SECRET_SCHEME) else {
+            return Ok(StoredSecret::Plain(secret.to_owned()));
+        };
+        let parts: Vec<&str> = encoded.split('$').collect();
+        let [_, iterations, salt, key] = parts.as_slice() else {
+            return Err(format!(
+                "Expected {}$<iterations>$<salt>$<key>",
+                SECRET_SCHEME
+            ));
+        };
+        Ok(StoredSecret::Derived {
+            iterations: parse_iterations(iterations)?,
+            salt: decode_hex(salt).ok_or("Invalid salt")?,
+            key: decode_key(key).ok_or("Invalid derived key")?,
+        })
+    }
+
+    pub fn encode(&self) -> String {
+        match self {
+            StoredSecret::Plain(secret) => secret.clone(),
+            StoredSecret::Derived {
+                iterations,
+                salt,
+                key,
+            } => format!(
+                "{}${}${}${}",
+                SECRET_SCHEME,
+                iterations,
+                encode_hex(salt),
+                encode_hex(key)
+            ),
+            StoredSecret::Scram(keys) => format!(
+                "{}${}${}${}${}",
+                SCRAM_SCHEME,
+                keys.iterations,
+                encode_hex(&keys.salt),
+                encode_hex(&keys.stored_key),
+                encode_hex(&keys.server_key)
+            ),
+        }
+    }
+
+    pub fn proof_key(&self) -> Option<&[u8]> {
+        match self {
+            StoredSecret::Plain(secret) => Some(secret.as_bytes()),
+            StoredSecret::Derived { key, .. } => Some(key),
+            StoredSecret::Scram(_) => None,
+        }
+    }
+
+    pub fn scram_keys(&self) -> Option<ScramKeys> {
+        match self {
+            StoredSecret::Plain(_) => None,
+            StoredSecret::Derived {
+                iterations,
+                salt,
+                key,
+            } => Some(ScramKeys::from_salted(*iterations, salt, key)),
+            StoredSecret::Scram(keys) => Some(keys.clone()),
+        }
+    }
+
+    pub fn salt(&self) -> Option<(u32, Vec<u8>)> {
+        match self {
+            StoredSecret::Plain(_) => None,
+            StoredSecret::Derived {
+                iterations, salt, ..
+            } => Some((*iterations, salt.clone())),
+            StoredSecret::Scram(keys) => Some((keys.iterations, keys.salt.clone())),
+        }
+    }
+
+    pub fn scheme(&self) -> &'static str {
+        match self {
+            StoredSecret::Plain(_) => "plain",
+            StoredSecret::Derived { .. } => SECRET_SCHEME,
+            StoredSecret::Scram(_) => SCRAM_SCHEME,
+        }
+    }
+}
+
+impl SecretSlot {
+    pub fn parse(slot: &str) -> Option<SecretSlot> {
+        match slot {
+            "primary" => Some(SecretSlot::Primary),
+            "secondary" => Some(SecretSlot::Secondary),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SecretSlot::Primary => "primary",
+            SecretSlot::Secondary => "secondary",
+        }
+    }
+}
+
+impl Credential {
+    pub fn is_valid_at(&self, now: u64) -> bool {
+        self.valid_from.is_none_or(|valid_from| valid_from <= now)
+            && self.valid_until.is_none_or(|valid_until| now < valid_until)
+    }
+}
+
+impl fmt::Display for Credential {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bound = |bound: Option<u64>| bound.map_or("-".to_owned(), |bound| bound.to_string());
+        write!(
+            f,
+            "{}\t{}\t{}\t{}",
+            self.slot.as_str(),
+            self.secret.scheme(),
+            bound(self.valid_from),
+            bound(self.valid_until)
+        )
+    }
+}
+
+pub fn parse_validity_window(args: &[&String]) -> Result<(Option<u64>, Option<u64>), String> {
+    let (mut valid_from, mut valid_until) = (None, None);
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("Missing value for {}", flag))?;
+        let value = value
+            .parse::<u64>()
+            .map_err(|_| format!("Invalid number for {}: {}", flag, value))?;
+        match flag.as_str() {
+            "--from" => valid_from = Some(value),
+            "--until" => valid_until = Some(value),
+            _ => return Err(format!("Unknown option {}", flag)),
+        }
+    }
+    Ok((valid_from, valid_until))
+}
+
+pub fn derive_secret(secret: &str, iterations: u32) -> StoredSecret {
+    derive_salted_secret(secret, iterations, random_bytes(), false)
+}
+
+pub fn derive_scram_secret(secret: &str, iterations: u32) -> StoredSecret {
+    derive_salted_secret(secret, iterations, random_bytes(), true)
+}
+
+pub fn derive_salted_secret(
+    secret: &str,
+    iterations: u32,
+    salt: Vec<u8>,
+    scram: bool,
+) -> StoredSecret {
+    let key = salted_key(secret, iterations, &salt);
+    if scram {
+        StoredSecret::Scram(ScramKeys::from_salted(iterations, &salt, &key))
+    } else {
+        StoredSecret::Derived {
+            iterations,
+            salt,
+            key,
+        }
+    }
+}
+
+fn salted_key(secret: &str, iterations: u32, salt: &[u8]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    pbkdf2_hmac::<Sha256>(secret.as_bytes(), salt, iterations, &mut key);
+    key
+}
+
+pub fn fake_salt(owner: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(FAKE_SALT_KEY.as_slice());
+    hasher.update(owner.as_bytes());
+    hasher.finalize()[..16].to_vec()
+}
+
+fn parse_scram(encoded: &str) -> Result<StoredSecret, String> {
+    let parts: Vec<&str> = encoded.split('$').collect();
+    let [_, iterations, salt, stored_key, server_key] = parts.as_slice() else {
+        return Err(format!(
+            "Expected {}$<iterations>$<salt>$<stored_key>$<server_key>",
+            SCRAM_SCHEME
+        ));
+    };
+    Ok(StoredSecret::Scram(ScramKeys {
+        iterations: parse_iterations(iterations)?,
+        salt: decode_hex(salt).ok_or("Invalid salt")?,
+        stored_key: decode_key(stored_key).ok_or("Invalid stored key")?,
+        server_key: decode_key(server_key).ok_or("Invalid server key")?,
+    }))
+}
+
+fn parse_iterations(iterations: &str) -> Result<u32, String> {
+    iterations
+        .parse()
+        .map_err(|_| "Invalid iteration count".to_owned())
+}
+
+pub fn random_bytes() -> Vec<u8> {
+    Sha256::digest(TextNonce::new().as_bytes())[..16].to_vec()
+}
+
+pub fn encode_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_key(data: &str) -> Option<[u8; 32]> {
+    decode_hex(data).and_then(|key| key.try_into().ok())
+}
+
+fn decode_hex(data: &str) -> Option<Vec<u8>> {
+    if !data.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..data.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(data.get(i..i + 2)?, 16).ok())
+        .collect()
+}
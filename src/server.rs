@@ -1,18 +1,41 @@
 use lazy_static::lazy_static;
 use textnonce::TextNonce;
 
-use crate::authstore::auth_user;
+use crate::anonymous::{end_guest_session, ANONYMOUS_OWNER};
+use crate::audit::{audit, AuditPeer};
+use crate::authstore::{auth_address, auth_known, auth_sub, auth_user, user_quota, AuthBackend};
+use crate::backpressure::{forget_writer, release_backpressure};
+use crate::cidr::{parse_networks, Cidr};
+use crate::config::CONFIG;
+use crate::errors::QuotaError;
+use crate::lockout::{locked_out_for, record_failure, record_success, FailureKey};
+use crate::message_string::read_str_with_len;
 use crate::messaging::{
+    authenticate_scram, is_anonymous_request, is_scram_request, is_token_request,
     read_arbitrary_message, read_auth_message, write_error_message, write_info_message,
+    write_scram_server_final,
 };
+use crate::messaging::{snapshot_end_frame, write_subscription_revoked_message};
+use crate::scheduler::now_millis;
+use crate::snapshot::snapshot;
+use crate::subscription::{OutboundMessage, Subscription};
+use crate::tokens::auth_token;
+use crate::transport::{
+    certificate_identities, has_client_certificate, tls_halves, ClientReader, ClientStream,
+    ClientWriter,
+};
+use futures_rustls::TlsAcceptor;
 use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 use std::{net::Shutdown, sync::Arc};
 
 use smol::{
     io::AsyncReadExt,
     net::{TcpListener, TcpStream},
     stream::StreamExt,
-    Task,
+    Task, Timer,
 };
 use smol::{
     lock::{Mutex, RwLock},
@@ -21,23 +44,69 @@ use smol::{
 pub static BROKER_NAME: &str = "rust-feeds";
 pub static NAME_LENGTH: u8 = BROKER_NAME.len() as u8;
 
+type ChannelSubs = HashMap<String, HashMap<u64, Arc<Subscription>>>;
+
+pub struct Session {
+    pub peer: AuditPeer,
+    pub writer: Arc<Mutex<ClientWriter>>,
+}
+
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(0);
+
 lazy_static! {
-    pub static ref SUBS: RwLock<HashMap<String, HashMap<String, Arc<Mutex<TcpStream>>>>> =
-        RwLock::new(HashMap::new());
+    pub static ref SUBS: RwLock<ChannelSubs> = RwLock::new(HashMap::new());
+    pub static ref SESSIONS: RwLock<HashMap<u64, Session>> = RwLock::new(HashMap::new());
+    pub static ref DENIED_NETWORKS: Vec<Cidr> = parse_networks(&CONFIG.deny_networks);
+    static ref ADDRESS_CONNECTIONS: std::sync::Mutex<HashMap<IpAddr, u64>> =
+        std::sync::Mutex::new(HashMap::new());
+}
+
+pub struct AddressSlot(IpAddr);
+
+impl AddressSlot {
+    fn acquire(remote: IpAddr, max_connections: u64) -> Result<AddressSlot, QuotaError> {
+        let mut connections = ADDRESS_CONNECTIONS.lock().unwrap();
+        let count = connections.entry(remote).or_insert(0);
+        if *count >= max_connections {
+            return Err(QuotaError::AddressConnectionLimit(max_connections, remote));
+        }
+        *count += 1;
+        Ok(AddressSlot(remote))
+    }
+}
+
+impl Drop for AddressSlot {
+    fn drop(&mut self) {
+        let mut connections = ADDRESS_CONNECTIONS.lock().unwrap();
+        if let Some(count) = connections.get_mut(&self.0) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&self.0);
+            }
+        }
+    }
 }
 
 pub struct Server {
     listener: TcpListener,
+    auth: Arc<dyn AuthBackend>,
+    tls: Option<TlsAcceptor>,
     listener_tasks: Vec<Task<Result<(), std::io::Error>>>,
 }
 
 impl Server {
-    pub async fn new(port: u32) -> Result<Server, std::io::Error> {
+    pub async fn new(
+        port: u32,
+        auth: Arc<dyn AuthBackend>,
+        tls: Option<TlsAcceptor>,
+    ) -> Result<Server, std::io::Error> {
         let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).await?;
         println!("Bound on port: {}", port);
 
         Ok(Server {
             listener,
+            auth,
+            tls,
             listener_tasks: vec![],
         })
     }
@@ -57,101 +126,709 @@ async fn loop_listen(
     println!("Listening on: {}", server_mtx.listener.local_addr()?);
 
     let listener = server_mtx.listener.clone();
+    let auth = Arc::clone(&server_mtx.auth);
+    let tls = server_mtx.tls.clone();
     drop(server_mtx);
 
     let mut incoming = listener.incoming();
 
     while let Some(stream) = incoming.next().await {
-        let stream = stream?;
+        let mut stream = stream?;
+        let session_id = NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed);
+        let mut slot = None;
+        if let Ok(remote) = stream.peer_addr() {
+            if DENIED_NETWORKS
+                .iter()
+                .any(|network| network.contains(remote.ip()))
+            {
+                let peer = AuditPeer {
+                    session_id: Some(session_id),
+                    remote: Some(remote),
+                    ..AuditPeer::default()
+                };
+                audit(&peer, "connect", None, "denied network");
+                println!("Rejected connection from denied address {}", remote.ip());
+                let _ = stream.shutdown(Shutdown::Both);
+                continue;
+            }
+            if let Some(max_connections) = CONFIG.max_connections_per_ip {
+                match AddressSlot::acquire(remote.ip(), max_connections) {
+                    Ok(acquired) => slot = Some(acquired),
+                    Err(limit_error) => {
+                        let peer = AuditPeer {
+                            session_id: Some(session_id),
+                            remote: Some(remote),
+                            ..AuditPeer::default()
+                        };
+                        let message = limit_error.to_string();
+                        audit(&peer, "connect", None, &message);
+                        if tls.is_none() {
+                            let _ = write_error_message(&mut stream, &message).await;
+                        }
+                        let _ = stream.shutdown(Shutdown::Both);
+                        continue;
+                    }
+                }
+            }
+        }
 
         let arc_serv = Arc::clone(&server);
         let conn_arc_exec = Arc::clone(&executor);
+        let conn_auth = Arc::clone(&auth);
 
-        executor
-            .spawn(handle_first_connection(arc_serv, stream, conn_arc_exec))
-            .detach();
+        match &tls {
+            Some(acceptor) => executor
+                .spawn(handle_tls_connection(
+                    arc_serv,
+                    stream,
+                    conn_arc_exec,
+                    conn_auth,
+                    acceptor.clone(),
+                    slot,
+                    session_id,
+                ))
+                .detach(),
+            None => executor
+                .spawn(handle_first_connection(
+                    arc_serv,
+                    ClientStream::Plain(stream),
+                    conn_arc_exec,
+                    conn_auth,
+                    slot,
+                    session_id,
+                ))
+                .detach(),
+        }
     }
     Ok(())
 }
 
 async fn handle_first_connection(
     server: Arc<Mutex<Server>>,
-    mut stream: TcpStream,
+    mut stream: ClientStream,
     executor: Arc<Executor<'static>>,
+    auth: Arc<dyn AuthBackend>,
+    slot: Option<AddressSlot>,
+    session_id: u64,
 ) {
-    let nonce: TextNonce;
-    match write_info_message(&mut stream).await {
-        Ok(n) => nonce = n,
+    let mut failure_keys = Vec::with_capacity(2);
+    let mut peer = AuditPeer {
+        session_id: Some(session_id),
+        remote: stream.peer_addr().ok(),
+        ..AuditPeer::default()
+    };
+    if let Some(remote) = peer.remote {
+        failure_keys.push(FailureKey::Ip(remote.ip()));
+    }
+    if reject_locked_out(&mut stream, &failure_keys, &peer).await {
+        return;
+    }
+
+    let nonce: TextNonce = match write_info_message(&mut stream).await {
+        Ok(n) => n,
         Err(_) => {
             let _ = stream.shutdown(Shutdown::Both);
             return;
         }
-    }
+    };
 
-    let auth_data: Vec<u8>;
-    match read_auth_message(&mut stream).await {
-        Ok(adata) => auth_data = adata,
+    let auth_data: Vec<u8> = match read_auth_message(&mut stream, &*auth).await {
+        Ok(adata) => adata,
         Err(_) => {
             let _ = stream.shutdown(Shutdown::Both);
             return;
         }
-    }
+    };
 
-    let owner_shift = 6 + auth_data[5] as usize;
-    let owner_name = &auth_data[6..owner_shift];
-    let owner_name_str: &str;
-    match std::str::from_utf8(owner_name) {
-        Ok(on) => owner_name_str = on,
-        Err(_) => return,
+    if !is_token_request(&auth_data) {
+        if let Ok((_, owner_name)) = read_str_with_len(&auth_data[5..]) {
+            failure_keys.push(FailureKey::Owner(owner_name.to_owned()));
+            peer.owner = Some(owner_name.to_owned());
+        }
+        if reject_locked_out(&mut stream, &failure_keys, &peer).await {
+            return;
+        }
     }
 
-    let user_sha = &auth_data[owner_shift..];
-    if auth_user(owner_name_str, nonce.as_bytes(), user_sha).await {
+    let method = if is_token_request(&auth_data) {
+        "token"
+    } else if is_scram_request(&auth_data) {
+        "SCRAM"
+    } else if is_anonymous_request(&auth_data) {
+        "anonymous access"
+    } else {
+        "secret"
+    };
+    let guest = is_anonymous_request(&auth_data);
+    let mut server_signature = None;
+    let authenticated = if is_token_request(&auth_data) {
+        match std::str::from_utf8(&auth_data[5..]) {
+            Ok(token) => auth_token(token).map(|(owner, grant)| (owner, Some(grant))),
+            Err(_) => None,
+        }
+    } else if is_scram_request(&auth_data) {
+        match authenticate_scram(&mut stream, &*auth, nonce.as_bytes(), &auth_data).await {
+            Ok(verified) => verified.map(|(owner, signature)| {
+                server_signature = Some(signature);
+                (owner, None)
+            }),
+            Err(_) => {
+                let _ = stream.shutdown(Shutdown::Both);
+                return;
+            }
+        }
+    } else if guest {
+        Some((ANONYMOUS_OWNER.to_owned(), None))
+    } else {
+        let (owner_len, owner_name_str) = match read_str_with_len(&auth_data[5..]) {
+            Ok(owner) => owner,
+            Err(_) => return,
+        };
+
+        let user_sha = &auth_data[6 + owner_len..];
+        if auth_user(&*auth, owner_name_str, nonce.as_bytes(), user_sha).await {
+            Some((owner_name_str.to_owned(), None))
+        } else {
+            None
+        }
+    };
+
+    let permitted = match (&authenticated, peer.remote) {
+        (Some((owner_name_str, _)), Some(remote)) if !guest => {
+            auth_address(&*auth, owner_name_str, remote.ip()).await
+        }
+        _ => true,
+    };
+    if let Some((owner_name_str, grant)) = authenticated.filter(|_| permitted) {
+        if let Some(signature) = server_signature {
+            if write_scram_server_final(&mut stream, &signature)
+                .await
+                .is_err()
+            {
+                let _ = stream.shutdown(Shutdown::Both);
+                return;
+            }
+        }
+        if !guest {
+            record_success(&owner_name_str).await;
+        }
+        peer.owner = Some(owner_name_str.clone());
+        peer.grant = grant;
+        peer.guest = guest;
+        audit(
+            &peer,
+            "auth",
+            None,
+            &format!("authenticated with {}", method),
+        );
         let mut server_mtx = server.lock().await;
-        let listen_future = executor.spawn(listen_to_client(stream));
+        let listen_future =
+            executor.spawn(listen_to_client(stream.into_halves(), peer, auth, slot));
         server_mtx.listener_tasks.push(listen_future);
         println!("User {} authenticated!", owner_name_str);
     } else {
-        if let Err(_) = write_error_message(&mut stream, "Authentication Error").await {
+        record_failure(&failure_keys).await;
+        let outcome = if permitted {
+            format!("failed {} authentication", method)
+        } else {
+            format!("{} authentication from a network not permitted", method)
+        };
+        audit(&peer, "auth", None, &outcome);
+        if write_error_message(&mut stream, "Authentication Error")
+            .await
+            .is_err()
+        {
             let _ = stream.shutdown(Shutdown::Both);
         }
     }
 }
 
-async fn listen_to_client(stream: TcpStream) -> Result<(), std::io::Error> {
-    let mut buff = [0u8; 4];
-    let mut reader_half = stream.clone();
-    let writer_half = Arc::new(Mutex::new(stream.clone()));
+async fn handle_tls_connection(
+    server: Arc<Mutex<Server>>,
+    stream: TcpStream,
+    executor: Arc<Executor<'static>>,
+    auth: Arc<dyn AuthBackend>,
+    acceptor: TlsAcceptor,
+    slot: Option<AddressSlot>,
+    session_id: u64,
+) {
+    let tcp = stream.clone();
+    let tls_stream = match acceptor.accept(stream).await {
+        Ok(tls_stream) => tls_stream,
+        Err(e) => {
+            if let Ok(peer) = tcp.peer_addr() {
+                println!("TLS handshake with {} failed: {}", peer, e);
+            }
+            let _ = tcp.shutdown(Shutdown::Both);
+            return;
+        }
+    };
+    if !has_client_certificate(&tls_stream) {
+        let stream = ClientStream::Tls(tcp, Box::new(tls_stream));
+        handle_first_connection(server, stream, executor, auth, slot, session_id).await;
+        return;
+    }
 
+    let mut owner = None;
+    for identity in certificate_identities(&tls_stream, &CONFIG.tls_identity) {
+        if auth_known(&*auth, &identity).await {
+            owner = Some(identity);
+            break;
+        }
+    }
+
+    let (reader_half, mut writer_half) = tls_halves(tcp, tls_stream);
+    let peer = AuditPeer {
+        session_id: Some(session_id),
+        owner: owner.clone(),
+        remote: writer_half.peer_addr().ok(),
+        ..AuditPeer::default()
+    };
+    let permitted = match (&owner, peer.remote) {
+        (Some(owner_name_str), Some(remote)) => {
+            auth_address(&*auth, owner_name_str, remote.ip()).await
+        }
+        _ => true,
+    };
+    if !permitted {
+        audit(
+            &peer,
+            "auth",
+            None,
+            "client certificate from a network not permitted",
+        );
+        let _ = write_error_message(&mut writer_half, "Authentication Error").await;
+        let _ = writer_half.shutdown(Shutdown::Both);
+    } else if let Some(owner_name_str) = owner {
+        audit(&peer, "auth", None, "authenticated with client certificate");
+        let mut server_mtx = server.lock().await;
+        let listen_future = executor.spawn(listen_to_client(
+            (reader_half, writer_half),
+            peer,
+            auth,
+            slot,
+        ));
+        server_mtx.listener_tasks.push(listen_future);
+        println!(
+            "User {} authenticated with client certificate!",
+            owner_name_str
+        );
+    } else {
+        audit(&peer, "auth", None, "unknown client certificate identity");
+        let _ = write_error_message(&mut writer_half, "Authentication Error").await;
+        let _ = writer_half.shutdown(Shutdown::Both);
+    }
+}
+
+async fn reject_locked_out(
+    stream: &mut ClientStream,
+    failure_keys: &[FailureKey],
+    peer: &AuditPeer,
+) -> bool {
+    let Some(remaining) = locked_out_for(failure_keys).await else {
+        return false;
+    };
+    audit(peer, "auth", None, "locked out");
+    let _ = write_error_message(
+        stream,
+        &format!(
+            "Too many failed authentication attempts. Retry in {} s.",
+            remaining.div_ceil(1000)
+        ),
+    )
+    .await;
+    let _ = stream.shutdown(Shutdown::Both);
+    true
+}
+
+async fn listen_to_client(
+    (reader_half, writer_half): (ClientReader, ClientWriter),
+    peer: AuditPeer,
+    auth: Arc<dyn AuthBackend>,
+    _slot: Option<AddressSlot>,
+) -> Result<(), std::io::Error> {
+    let max_connections = user_quota(&*auth, &peer).await.max_connections;
+    let expires_at = peer.grant.as_ref().map(|grant| grant.expires_at);
+    let session_id = peer.session_id.unwrap_or_default();
+    let writer_half = Arc::new(Mutex::new(writer_half));
+    {
+        let mut sessions = SESSIONS.write().await;
+        let mut limit_error = None;
+        if let Some(max_connections) = max_connections {
+            let connections = sessions
+                .values()
+                .filter(|session| shares_connection_limit(&session.peer, &peer))
+                .count();
+            if connections as u64 >= max_connections {
+                limit_error = Some(match (peer.guest, peer.remote) {
+                    (true, Some(remote)) => {
+                        QuotaError::AddressConnectionLimit(max_connections, remote.ip())
+                    }
+                    _ => QuotaError::ConnectionLimit(max_connections),
+                });
+            }
+        }
+        if let Some(limit_error) = limit_error {
+            drop(sessions);
+            let mut stream = writer_half.lock().await;
+            let message = limit_error.to_string();
+            audit(&peer, "session_start", None, &message);
+            write_error_message(&mut *stream, &message).await?;
+            let _ = stream.shutdown(Shutdown::Both);
+            return Ok(());
+        }
+        sessions.insert(
+            session_id,
+            Session {
+                peer: peer.clone(),
+                writer: writer_half.clone(),
+            },
+        );
+    }
+    audit(&peer, "session_start", None, "allowed");
+    let _guard = SessionGuard {
+        session_id,
+        guest: peer.guest,
+        writer: writer_half.clone(),
+    };
+
+    let result = match expires_at {
+        None => read_client_messages(reader_half, &writer_half, &*auth, &peer).await,
+        Some(expires_at) => {
+            smol::future::or(
+                read_client_messages(reader_half, &writer_half, &*auth, &peer),
+                expire_session(&peer, &writer_half, expires_at),
+            )
+            .await
+        }
+    };
+    let outcome = match &result {
+        Err(e) if e.kind() != std::io::ErrorKind::UnexpectedEof => e.to_string(),
+        _ => "closed".to_owned(),
+    };
+    audit(&peer, "session_end", None, &outcome);
+    result
+}
+
+fn shares_connection_limit(session: &AuditPeer, peer: &AuditPeer) -> bool {
+    if peer.guest {
+        session.guest
+            && session.remote.map(|remote| remote.ip()) == peer.remote.map(|remote| remote.ip())
+    } else {
+        !session.guest && session.owner == peer.owner
+    }
+}
+
+struct SessionGuard {
+    session_id: u64,
+    guest: bool,
+    writer: Arc<Mutex<ClientWriter>>,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        let session_id = self.session_id;
+        let guest = self.guest;
+        let writer = self.writer.clone();
+        smol::spawn(async move {
+            SESSIONS.write().await.remove(&session_id);
+            remove_session_subs(session_id, &writer).await;
+            if guest {
+                end_guest_session(session_id).await;
+            }
+        })
+        .detach();
+    }
+}
+
+async fn expire_session(
+    peer: &AuditPeer,
+    writer_half: &Arc<Mutex<ClientWriter>>,
+    expires_at: u64,
+) -> Result<(), std::io::Error> {
+    Timer::after(Duration::from_millis(
+        expires_at.saturating_sub(now_millis()),
+    ))
+    .await;
+    if let Some(owner) = &peer.owner {
+        println!("Token of user {} expired", owner);
+    }
+    audit(peer, "token_expired", None, "disconnected");
+    let mut stream = writer_half.lock().await;
+    let _ = write_error_message(&mut *stream, "Token expired").await;
+    let _ = stream.shutdown(Shutdown::Both);
+    Ok(())
+}
+
+async fn read_client_messages(
+    mut reader_half: ClientReader,
+    writer_half: &Arc<Mutex<ClientWriter>>,
+    auth: &dyn AuthBackend,
+    peer: &AuditPeer,
+) -> Result<(), std::io::Error> {
+    let mut buff = [0u8; 4];
     loop {
         reader_half.read_exact(&mut buff).await?;
-        read_arbitrary_message(&mut reader_half, &writer_half, u32::from_be_bytes(buff)).await?;
+        read_arbitrary_message(
+            &mut reader_half,
+            writer_half,
+            u32::from_be_bytes(buff),
+            auth,
+            peer,
+        )
+        .await?;
+    }
+}
+
+async fn remove_session_subs(session_id: u64, stream_writer: &Arc<Mutex<ClientWriter>>) {
+    let mut channels = Vec::new();
+    {
+        let mut subs_lock = SUBS.write().await;
+        for (channel, chan_map) in subs_lock.iter_mut() {
+            if chan_map.remove(&session_id).is_some() {
+                channels.push(channel.clone());
+            }
+        }
+        subs_lock.retain(|_, chan_map| !chan_map.is_empty());
+    }
+    forget_writer(stream_writer).await;
+    for channel in channels {
+        release_backpressure(&channel).await;
+    }
+}
+
+pub async fn watch_auth(auth: Arc<dyn AuthBackend>, interval: Duration) {
+    loop {
+        Timer::after(interval).await;
+        match auth.reload().await {
+            Ok(Some(removed_owners)) => revalidate_sessions(&*auth, &removed_owners).await,
+            Ok(None) => (),
+            Err(e) => println!("Failed to reload auth backend: {}", e),
+        }
+    }
+}
+
+pub async fn revalidate_sessions(auth: &dyn AuthBackend, removed_owners: &[String]) {
+    let peers: HashMap<u64, AuditPeer> = SESSIONS
+        .read()
+        .await
+        .iter()
+        .map(|(session_id, session)| (*session_id, session.peer.clone()))
+        .collect();
+    let mut revoked = Vec::new();
+    {
+        let mut subs_lock = SUBS.write().await;
+        for (channel, chan_map) in subs_lock.iter_mut() {
+            let mut denied = Vec::new();
+            for session_id in chan_map.keys() {
+                let Some(peer) = peers.get(session_id) else {
+                    continue;
+                };
+                if !auth_sub(auth, peer, channel).await {
+                    denied.push((*session_id, peer));
+                }
+            }
+            for (session_id, peer) in denied {
+                if let Some(subscription) = chan_map.remove(&session_id) {
+                    revoked.push((channel.clone(), peer, subscription));
+                }
+            }
+        }
+        subs_lock.retain(|_, chan_map| !chan_map.is_empty());
+    }
+
+    for (channel, peer, subscription) in revoked {
+        println!(
+            "Revoked subscription of {} to {}",
+            subscription.owner, channel
+        );
+        audit(peer, "subscription_revoked", Some(&channel), "revoked");
+        {
+            let mut stream = subscription.writer.lock().await;
+            let _ = write_subscription_revoked_message(&mut stream, &channel).await;
+        }
+        release_backpressure(&channel).await;
+    }
+
+    let kicked: Vec<(AuditPeer, Arc<Mutex<ClientWriter>>)> = SESSIONS
+        .read()
+        .await
+        .values()
+        .filter(|session| {
+            !session.peer.guest
+                && session
+                    .peer
+                    .owner
+                    .as_ref()
+                    .is_some_and(|owner| removed_owners.contains(owner))
+        })
+        .map(|session| (session.peer.clone(), session.writer.clone()))
+        .collect();
+    for (peer, writer) in kicked {
+        if let Some(owner) = &peer.owner {
+            println!("Disconnecting session of removed user {}", owner);
+        }
+        let mut stream = writer.lock().await;
+        audit(&peer, "session_revoked", None, "user removed");
+        let _ = write_error_message(&mut *stream, "Session revoked").await;
+        let _ = stream.shutdown(Shutdown::Both);
     }
 }
 
+pub async fn subscription_count(peer: &AuditPeer, except_chan: &str) -> usize {
+    let owner = peer.owner.as_deref().unwrap_or_default();
+    let subs_lock = SUBS.read().await;
+    subs_lock
+        .iter()
+        .filter(|(channel, chan_map)| {
+            *channel != except_chan
+                && chan_map.values().any(|subscription| {
+                    subscription.owner == owner && subscription.guest == peer.guest
+                })
+        })
+        .count()
+}
+
 #[inline(always)]
-pub async fn add_sub(sub_chan: &str, sub_name: &str, stream_writer: Arc<Mutex<TcpStream>>) {
+pub async fn add_sub(sub_chan: &str, peer: &AuditPeer, stream_writer: Arc<Mutex<ClientWriter>>) {
+    let session_id = peer.session_id.unwrap_or_default();
+    let subscription = Subscription::start(sub_chan, peer, stream_writer, None).await;
     let mut subs_lock = SUBS.write().await;
     if let Some(chan_map) = subs_lock.get_mut(sub_chan) {
-        chan_map.insert(sub_name.to_owned(), stream_writer);
+        chan_map.insert(session_id, subscription);
     } else {
         subs_lock.insert(
             sub_chan.to_owned(),
-            HashMap::from([(sub_name.to_owned(), stream_writer)]),
+            HashMap::from([(session_id, subscription)]),
         );
     }
 }
 
 #[inline(always)]
-pub async fn remove_sub(sub_chan: &str, sub_name: &str) {
+pub async fn add_snapshot_sub(
+    sub_chan: &str,
+    peer: &AuditPeer,
+    stream_writer: Arc<Mutex<ClientWriter>>,
+) {
+    let subscription = Subscription::start(sub_chan, peer, stream_writer, None).await;
     let mut subs_lock = SUBS.write().await;
-    if let Some(chan_map) = subs_lock.get_mut(sub_chan) {
-        if let Some(stream) = chan_map.get_mut(sub_name) {
-            {
-                let stream_lock = stream.lock().await;
-                let _ = stream_lock.shutdown(Shutdown::Read);
+    let mut messages = snapshot(sub_chan).await;
+    messages.push(Arc::new(OutboundMessage::new(
+        snapshot_end_frame(sub_chan),
+        None,
+    )));
+    subscription.replay(messages).await;
+    subs_lock
+        .entry(sub_chan.to_owned())
+        .or_default()
+        .insert(peer.session_id.unwrap_or_default(), subscription);
+}
+
+#[inline(always)]
+pub async fn grant_sub_credit(
+    sub_chan: &str,
+    peer: &AuditPeer,
+    stream_writer: Arc<Mutex<ClientWriter>>,
+    credit: u64,
+) {
+    let session_id = peer.session_id.unwrap_or_default();
+    let subscription = {
+        let mut subs_lock = SUBS.write().await;
+        let chan_map = subs_lock.entry(sub_chan.to_owned()).or_default();
+        match chan_map.get(&session_id) {
+            Some(subscription) => subscription.clone(),
+            None => {
+                let subscription =
+                    Subscription::start(sub_chan, peer, stream_writer, Some(0)).await;
+                chan_map.insert(session_id, subscription.clone());
+                subscription
             }
-            chan_map.remove(sub_name);
         }
+    };
+    subscription.grant_credit(credit).await;
+}
+
+#[inline(always)]
+pub async fn remove_sub(sub_chan: &str, peer: &AuditPeer) -> bool {
+    let removed = {
+        let mut subs_lock = SUBS.write().await;
+        let Some(chan_map) = subs_lock.get_mut(sub_chan) else {
+            return false;
+        };
+        let removed = chan_map
+            .remove(&peer.session_id.unwrap_or_default())
+            .is_some();
+        if chan_map.is_empty() {
+            subs_lock.remove(sub_chan);
+        }
+        removed
+    };
+    if removed {
+        release_backpressure(sub_chan).await;
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messaging::push_publish_data_to_streams;
+    use crate::transport::plain_halves;
+    use smol::io::AsyncReadExt;
+
+    async fn guest_connection(
+        listener: &TcpListener,
+        session_id: u64,
+    ) -> (AuditPeer, Arc<Mutex<ClientWriter>>, TcpStream) {
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, remote) = listener.accept().await.unwrap();
+        let (_, writer) = plain_halves(stream);
+        let peer = AuditPeer {
+            session_id: Some(session_id),
+            owner: Some(ANONYMOUS_OWNER.to_owned()),
+            remote: Some(remote),
+            guest: true,
+            ..AuditPeer::default()
+        };
+        (peer, Arc::new(Mutex::new(writer)), client)
+    }
+
+    #[test]
+    fn guests_on_the_same_channel_all_receive_messages() {
+        smol::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let (first, first_writer, mut first_client) = guest_connection(&listener, 1001).await;
+            let (second, second_writer, mut second_client) =
+                guest_connection(&listener, 1002).await;
+            add_sub("guest-status", &first, first_writer).await;
+            add_sub("guest-status", &second, second_writer).await;
+            assert_eq!(SUBS.read().await["guest-status"].len(), 2);
+
+            let frame = b"\x00\x00\x00\x05\x03".to_vec();
+            push_publish_data_to_streams(
+                "guest-status",
+                vec![OutboundMessage::new(frame.clone(), None)],
+            )
+            .await
+            .unwrap();
+            for client in [&mut first_client, &mut second_client] {
+                let mut received = vec![0u8; frame.len()];
+                smol::future::or(
+                    async { client.read_exact(&mut received).await.unwrap() },
+                    async {
+                        Timer::after(Duration::from_secs(2)).await;
+                        panic!("guest did not receive the message");
+                    },
+                )
+                .await;
+                assert_eq!(received, frame);
+            }
+
+            assert!(remove_sub("guest-status", &first).await);
+            assert!(!remove_sub("guest-status", &first).await);
+            assert_eq!(SUBS.read().await["guest-status"].len(), 1);
+        });
     }
 }
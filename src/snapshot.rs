@@ -0,0 +1,35 @@
+use crate::channel_config::channel_config;
+use crate::expiry::is_expired;
+use crate::subscription::OutboundMessage;
+use lazy_static::lazy_static;
+use smol::lock::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+type LastValues = HashMap<String, HashMap<String, Arc<OutboundMessage>>>;
+
+lazy_static! {
+    static ref LAST_VALUES: Mutex<LastValues> = Mutex::new(HashMap::new());
+}
+
+pub async fn record_last_values(channel: &str, messages: &[Arc<OutboundMessage>]) {
+    if !channel_config(channel).await.snapshot {
+        return;
+    }
+    let mut last_values = LAST_VALUES.lock().await;
+    let values = last_values.entry(channel.to_owned()).or_default();
+    for message in messages {
+        if let Some(key) = &message.key {
+            values.insert(key.clone(), message.clone());
+        }
+    }
+}
+
+pub async fn snapshot(channel: &str) -> Vec<Arc<OutboundMessage>> {
+    let mut last_values = LAST_VALUES.lock().await;
+    let Some(values) = last_values.get_mut(channel) else {
+        return Vec::new();
+    };
+    values.retain(|_, message| !is_expired(message.expires_at));
+    values.values().cloned().collect()
+}
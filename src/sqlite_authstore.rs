@@ -1,99 +1,604 @@
-use crate::authstore::{AuthDbObject, AuthObject, AuthStoreSource, AUTH_MAP};
-use rusqlite::Connection;
+use crate::authstore::{
+    modified_at, Action, AuthBackend, AuthDbObject, AuthFuture, AuthObject, Effect, NetworkRule,
+    Rule,
+};
+use crate::cidr::Cidr;
+use crate::errors::AuthStoreError;
+use crate::memory_authstore::MemoryAuthStore;
+use crate::migrations::{run_migrations, Migration};
+use crate::quotas::Quota;
+use crate::secrets::{
+    derive_salted_secret, random_bytes, Credential, SecretSlot, StoredSecret, DEFAULT_ITERATIONS,
+};
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::SystemTime;
 
-pub struct SqliteAuthStore {}
+pub struct SqliteAuthStore {
+    path: String,
+    cache: MemoryAuthStore,
+    last_modified: Mutex<Option<SystemTime>>,
+}
 
-static QUERY: &str = "SELECT owner, secret, allow_sub, allow_pub
-             FROM auth_objects;";
+static AUTH_MIGRATIONS: &[Migration] = &[
+    create_acl_tables,
+    import_auth_objects,
+    create_role_tables,
+    create_quota_table,
+    create_secret_table,
+    create_network_table,
+];
 
-impl AuthStoreSource for SqliteAuthStore {
-    async fn feed_cache(&self) {
-        let conn = Connection::open("./sqlite/auth.db").unwrap();
+fn create_acl_tables(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute_batch(
+        "CREATE TABLE users (
+            owner TEXT PRIMARY KEY NOT NULL CHECK (length(owner) BETWEEN 1 AND 255),
+            secret TEXT NOT NULL
+        );
+        CREATE TABLE channels (
+            pattern TEXT PRIMARY KEY NOT NULL CHECK (length(pattern) BETWEEN 1 AND 255)
+        );
+        CREATE TABLE grants (
+            owner TEXT NOT NULL REFERENCES users(owner) ON DELETE CASCADE,
+            pattern TEXT NOT NULL REFERENCES channels(pattern) ON DELETE CASCADE,
+            action TEXT NOT NULL CHECK (action IN ('pub', 'sub')),
+            PRIMARY KEY (owner, pattern, action)
+        );",
+    )
+}
 
-        let mut stmt = conn.prepare(QUERY).unwrap();
+fn import_auth_objects(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    let legacy_exists: bool = tx.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'auth_objects');",
+        [],
+        |row| row.get(0),
+    )?;
+    if !legacy_exists {
+        return Ok(());
+    }
 
-        let owners = stmt
-            .query_map([], |row| {
-                Ok(AuthDbObject {
-                    owner: row.get(0)?,
-                    secret: row.get(1)?,
-                    allow_sub: row.get(2)?,
-                    allow_pub: row.get(3)?,
-                })
+    let rows: Vec<AuthDbObject> = {
+        let mut stmt =
+            tx.prepare("SELECT owner, secret, allow_sub, allow_pub FROM auth_objects;")?;
+        let rows = stmt.query_map([], |row| {
+            Ok(AuthDbObject {
+                owner: row.get(0)?,
+                secret: row.get(1)?,
+                allow_sub: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+                allow_pub: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
             })
-            .unwrap();
+        })?;
+        rows.collect::<Result<_, _>>()?
+    };
+
+    for row in &rows {
+        if row.owner.is_empty() || row.owner.len() > 255 {
+            println!("Skipping legacy user with invalid name: {:?}", row.owner);
+            continue;
+        }
+        tx.execute(
+            "INSERT OR REPLACE INTO users (owner, secret) VALUES (?1, ?2);",
+            [&row.owner, &row.secret],
+        )?;
+        for (action, patterns) in [("sub", &row.allow_sub), ("pub", &row.allow_pub)] {
+            for pattern in patterns.split(',').map(str::trim) {
+                if pattern.is_empty() || pattern.len() > 255 {
+                    continue;
+                }
+                tx.execute(
+                    "INSERT OR IGNORE INTO channels (pattern) VALUES (?1);",
+                    [pattern],
+                )?;
+                tx.execute(
+                    "INSERT OR IGNORE INTO grants (owner, pattern, action) VALUES (?1, ?2, ?3);",
+                    [&row.owner, pattern, action],
+                )?;
+            }
+        }
+    }
+    tx.execute_batch("ALTER TABLE auth_objects RENAME TO auth_objects_imported;")?;
+    println!("Imported {} users from auth_objects", rows.len());
+    Ok(())
+}
+
+fn create_role_tables(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute_batch(
+        "ALTER TABLE grants ADD COLUMN effect TEXT NOT NULL DEFAULT 'allow'
+            CHECK (effect IN ('allow', 'deny'));
+        CREATE TABLE roles (
+            role TEXT PRIMARY KEY NOT NULL CHECK (length(role) BETWEEN 1 AND 255)
+        );
+        CREATE TABLE role_members (
+            owner TEXT NOT NULL REFERENCES users(owner) ON DELETE CASCADE,
+            role TEXT NOT NULL REFERENCES roles(role) ON DELETE CASCADE,
+            PRIMARY KEY (owner, role)
+        );
+        CREATE TABLE role_grants (
+            role TEXT NOT NULL REFERENCES roles(role) ON DELETE CASCADE,
+            pattern TEXT NOT NULL REFERENCES channels(pattern) ON DELETE CASCADE,
+            action TEXT NOT NULL CHECK (action IN ('pub', 'sub')),
+            effect TEXT NOT NULL DEFAULT 'allow' CHECK (effect IN ('allow', 'deny')),
+            PRIMARY KEY (role, pattern, action)
+        );",
+    )
+}
+
+fn create_quota_table(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute_batch(
+        "CREATE TABLE user_quotas (
+            owner TEXT PRIMARY KEY NOT NULL REFERENCES users(owner) ON DELETE CASCADE,
+            max_connections INTEGER CHECK (max_connections >= 0),
+            max_subscriptions INTEGER CHECK (max_subscriptions >= 0),
+            max_msgs_per_sec INTEGER CHECK (max_msgs_per_sec >= 0),
+            max_bytes_per_sec INTEGER CHECK (max_bytes_per_sec >= 0),
+            max_message_size INTEGER CHECK (max_message_size >= 0)
+        );",
+    )
+}
+
+fn create_secret_table(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute_batch(
+        "CREATE TABLE user_secrets (
+            owner TEXT NOT NULL REFERENCES users(owner) ON DELETE CASCADE,
+            slot TEXT NOT NULL CHECK (slot IN ('primary', 'secondary')),
+            secret TEXT NOT NULL,
+            valid_from INTEGER CHECK (valid_from >= 0),
+            valid_until INTEGER CHECK (valid_until >= 0),
+            PRIMARY KEY (owner, slot)
+        );
+        INSERT INTO user_secrets (owner, slot, secret) SELECT owner, 'primary', secret FROM users;
+        ALTER TABLE users DROP COLUMN secret;",
+    )
+}
+
+fn create_network_table(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute_batch(
+        "CREATE TABLE user_networks (
+            owner TEXT NOT NULL REFERENCES users(owner) ON DELETE CASCADE,
+            cidr TEXT NOT NULL,
+            effect TEXT NOT NULL DEFAULT 'allow' CHECK (effect IN ('allow', 'deny')),
+            PRIMARY KEY (owner, cidr)
+        );",
+    )
+}
+
+static SECRETS_QUERY: &str =
+    "SELECT owner, slot, secret, valid_from, valid_until FROM user_secrets;";
+
+static QUOTAS_QUERY: &str = "SELECT owner, max_connections, max_subscriptions, max_msgs_per_sec,
+    max_bytes_per_sec, max_message_size FROM user_quotas;";
+
+static RULES_QUERY: &str = "SELECT owner, NULL, pattern, action, effect FROM grants
+    UNION ALL
+    SELECT m.owner, g.role, g.pattern, g.action, g.effect
+        FROM role_members m JOIN role_grants g ON g.role = m.role;";
 
-        let mut map = AUTH_MAP.write().await;
-        for owner in owners {
-            let owner = owner.unwrap();
-            map.insert(
-                owner.owner,
+impl SqliteAuthStore {
+    pub fn open(path: &str) -> Result<SqliteAuthStore, AuthStoreError> {
+        let mut store = SqliteAuthStore {
+            path: path.to_owned(),
+            cache: MemoryAuthStore::new(HashMap::new()),
+            last_modified: Mutex::new(None),
+        };
+        store.cache = MemoryAuthStore::new(store.load_auth_objects()?);
+        store.last_modified = Mutex::new(store.modified_at());
+        Ok(store)
+    }
+
+    fn connect(&self) -> Result<Connection, rusqlite::Error> {
+        let mut conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        run_migrations(&mut conn, AUTH_MIGRATIONS)?;
+        Ok(conn)
+    }
+
+    fn load_auth_objects(&self) -> Result<HashMap<String, AuthObject>, rusqlite::Error> {
+        let conn = self.connect()?;
+        let mut users_stmt = conn.prepare("SELECT owner FROM users;")?;
+        let users = users_stmt.query_map([], |row| row.get::<_, String>(0))?;
+
+        let mut auth_objects = HashMap::with_capacity(32);
+        for owner in users {
+            auth_objects.insert(
+                owner?,
                 AuthObject {
-                    secret: owner.secret,
-                    allow_sub: owner
-                        .allow_sub
-                        .split(",")
-                        .map(|s| s.trim().to_string())
-                        .collect(),
-                    allow_pub: owner
-                        .allow_pub
-                        .split(",")
-                        .map(|s| s.trim().to_string())
-                        .collect(),
+                    secrets: Vec::new(),
+                    sub_rules: Vec::new(),
+                    pub_rules: Vec::new(),
+                    networks: Vec::new(),
+                    quota: Quota::default(),
                 },
             );
         }
-    }
 
-    async fn update_cache(&self) {
-        let conn = Connection::open("/app/sqlite/auth.db").unwrap();
-        let mut stmt = conn.prepare(QUERY).unwrap();
+        let mut secrets_stmt = conn.prepare(SECRETS_QUERY)?;
+        let secrets = secrets_stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, Option<i64>>(3)?,
+                row.get::<_, Option<i64>>(4)?,
+            ))
+        })?;
+        for secret in secrets {
+            let (owner, slot, secret, valid_from, valid_until) = secret?;
+            let Some(auth_object) = auth_objects.get_mut(&owner) else {
+                continue;
+            };
+            let (Some(slot), Ok(secret)) = (SecretSlot::parse(&slot), StoredSecret::parse(&secret))
+            else {
+                println!("Skipping invalid {} secret of user {}", slot, owner);
+                continue;
+            };
+            auth_object.secrets.push(Credential {
+                slot,
+                secret,
+                valid_from: valid_from.map(|valid_from| valid_from as u64),
+                valid_until: valid_until.map(|valid_until| valid_until as u64),
+            });
+        }
 
-        let owners = stmt
-            .query_map([], |row| {
-                Ok(AuthDbObject {
-                    owner: row.get(0)?,
-                    secret: row.get(1)?,
-                    allow_sub: row.get(2)?,
-                    allow_pub: row.get(3)?,
-                })
-            })
-            .unwrap();
+        let mut rules_stmt = conn.prepare(RULES_QUERY)?;
+        let rules = rules_stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, Option<String>>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, String>(4)?,
+            ))
+        })?;
+        for rule in rules {
+            let (owner, role, pattern, action, effect) = rule?;
+            let Some(auth_object) = auth_objects.get_mut(&owner) else {
+                continue;
+            };
+            let rule = Rule {
+                pattern,
+                effect: match effect.as_str() {
+                    "deny" => Effect::Deny,
+                    _ => Effect::Allow,
+                },
+                role,
+            };
+            match action.as_str() {
+                "pub" => auth_object.pub_rules.push(rule),
+                _ => auth_object.sub_rules.push(rule),
+            }
+        }
 
-        let map = AUTH_MAP.read().await;
+        let mut networks_stmt = conn.prepare("SELECT owner, cidr, effect FROM user_networks;")?;
+        let networks = networks_stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+            ))
+        })?;
+        for network in networks {
+            let (owner, cidr, effect) = network?;
+            let Some(auth_object) = auth_objects.get_mut(&owner) else {
+                continue;
+            };
+            match Cidr::parse(&cidr) {
+                Ok(network) => auth_object.networks.push(NetworkRule {
+                    network,
+                    effect: match effect.as_str() {
+                        "deny" => Effect::Deny,
+                        _ => Effect::Allow,
+                    },
+                }),
+                Err(e) => println!("Skipping network of user {}: {}", owner, e),
+            }
+        }
+
+        let mut quotas_stmt = conn.prepare(QUOTAS_QUERY)?;
+        let quotas = quotas_stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                Quota {
+                    max_connections: row.get(1)?,
+                    max_subscriptions: row.get(2)?,
+                    max_msgs_per_sec: row.get(3)?,
+                    max_bytes_per_sec: row.get(4)?,
+                    max_message_size: row.get(5)?,
+                },
+            ))
+        })?;
+        for quota in quotas {
+            let (owner, quota) = quota?;
+            if let Some(auth_object) = auth_objects.get_mut(&owner) {
+                auth_object.quota = quota;
+            }
+        }
+
+        for auth_object in auth_objects.values_mut() {
+            auth_object
+                .secrets
+                .sort_by_key(|credential| credential.slot);
+            auth_object.pub_rules.sort();
+            auth_object.sub_rules.sort();
+            auth_object.networks.sort();
+        }
+        Ok(auth_objects)
+    }
+
+    pub fn migrate_secrets(&self, scram: bool) -> Result<usize, rusqlite::Error> {
+        let conn = self.connect()?;
+        let rows: Vec<(String, String, String)> = {
+            let mut stmt = conn.prepare("SELECT owner, slot, secret FROM user_secrets;")?;
+            let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+            rows.collect::<Result<_, rusqlite::Error>>()?
+        };
+
+        let mut salts: HashMap<String, (u32, Vec<u8>)> = HashMap::new();
+        for (owner, _, secret) in &rows {
+            if let Some(salt) = StoredSecret::parse(secret)
+                .ok()
+                .and_then(|secret| secret.salt())
+            {
+                salts.entry(owner.clone()).or_insert(salt);
+            }
+        }
 
-        let diff_owners: Vec<AuthDbObject> = owners
-            .filter_map(|owner_result| match owner_result {
-                Ok(owner) => {
-                    if !map.contains_key(&owner.owner) {
-                        Some(owner)
-                    } else {
-                        None
+        let mut migrated = Vec::new();
+        for (owner, slot, secret) in rows {
+            match StoredSecret::parse(&secret) {
+                Ok(StoredSecret::Plain(secret)) => {
+                    let (iterations, salt) = salts
+                        .entry(owner.clone())
+                        .or_insert_with(|| (DEFAULT_ITERATIONS, random_bytes()))
+                        .clone();
+                    let secret = derive_salted_secret(&secret, iterations, salt, scram);
+                    migrated.push((owner, slot, secret));
+                }
+                Ok(derived @ StoredSecret::Derived { .. }) if scram => {
+                    if let Some(keys) = derived.scram_keys() {
+                        migrated.push((owner, slot, StoredSecret::Scram(keys)));
                     }
                 }
-                Err(_) => None,
-            })
-            .collect();
+                _ => (),
+            }
+        }
 
-        let mut map = AUTH_MAP.write().await;
-        for owner in diff_owners {
-            let owner = owner;
-            map.insert(
-                owner.owner,
-                AuthObject {
-                    secret: owner.secret,
-                    allow_sub: owner
-                        .allow_sub
-                        .split(",")
-                        .map(|s| s.trim().to_string())
-                        .collect(),
-                    allow_pub: owner
-                        .allow_pub
-                        .split(",")
-                        .map(|s| s.trim().to_string())
-                        .collect(),
+        for (owner, slot, secret) in &migrated {
+            conn.execute(
+                "UPDATE user_secrets SET secret = ?1 WHERE owner = ?2 AND slot = ?3;",
+                [secret.encode(), owner.clone(), slot.clone()],
+            )?;
+        }
+        Ok(migrated.len())
+    }
+
+    pub fn set_secret(
+        &self,
+        owner: &str,
+        slot: SecretSlot,
+        secret: &str,
+        scram: bool,
+        valid_from: Option<u64>,
+        valid_until: Option<u64>,
+    ) -> Result<bool, rusqlite::Error> {
+        let mut conn = self.connect()?;
+        if !user_exists(&conn, owner)? {
+            return Ok(false);
+        }
+        let tx = conn.transaction()?;
+        let other: Option<(String, String)> = tx
+            .query_row(
+                "SELECT slot, secret FROM user_secrets WHERE owner = ?1 AND slot != ?2;",
+                [owner, slot.as_str()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        let other = other
+            .and_then(|(other_slot, other)| Some((other_slot, StoredSecret::parse(&other).ok()?)));
+        let (iterations, salt) = other
+            .as_ref()
+            .and_then(|(_, other)| other.salt())
+            .unwrap_or_else(|| (DEFAULT_ITERATIONS, random_bytes()));
+        if let Some((other_slot, StoredSecret::Plain(other))) = &other {
+            let other = derive_salted_secret(other, iterations, salt.clone(), scram);
+            tx.execute(
+                "UPDATE user_secrets SET secret = ?1 WHERE owner = ?2 AND slot = ?3;",
+                params![other.encode(), owner, other_slot],
+            )?;
+        }
+        let secret = derive_salted_secret(secret, iterations, salt, scram);
+        tx.execute(
+            "INSERT OR REPLACE INTO user_secrets (owner, slot, secret, valid_from, valid_until)
+                VALUES (?1, ?2, ?3, ?4, ?5);",
+            params![
+                owner,
+                slot.as_str(),
+                secret.encode(),
+                valid_from.map(|valid_from| valid_from as i64),
+                valid_until.map(|valid_until| valid_until as i64)
+            ],
+        )?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    pub fn promote_secret(&self, owner: &str) -> Result<bool, rusqlite::Error> {
+        let mut conn = self.connect()?;
+        let tx = conn.transaction()?;
+        let secondary = tx
+            .query_row(
+                "SELECT secret, valid_from, valid_until FROM user_secrets
+                    WHERE owner = ?1 AND slot = 'secondary';",
+                [owner],
+                |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, Option<i64>>(1)?,
+                        row.get::<_, Option<i64>>(2)?,
+                    ))
                 },
-            );
+            )
+            .optional()?;
+        let Some((secret, valid_from, valid_until)) = secondary else {
+            return Ok(false);
+        };
+        tx.execute(
+            "DELETE FROM user_secrets WHERE owner = ?1 AND slot = 'secondary';",
+            [owner],
+        )?;
+        tx.execute(
+            "UPDATE user_secrets SET slot = 'secondary' WHERE owner = ?1 AND slot = 'primary';",
+            [owner],
+        )?;
+        tx.execute(
+            "INSERT INTO user_secrets (owner, slot, secret, valid_from, valid_until)
+                VALUES (?1, 'primary', ?2, ?3, ?4);",
+            params![owner, secret, valid_from, valid_until],
+        )?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    pub fn retire_secret(&self, owner: &str, slot: SecretSlot) -> Result<bool, rusqlite::Error> {
+        let mut conn = self.connect()?;
+        let tx = conn.transaction()?;
+        let retired = tx.execute(
+            "DELETE FROM user_secrets WHERE owner = ?1 AND slot = ?2;",
+            [owner, slot.as_str()],
+        )?;
+        if slot == SecretSlot::Primary {
+            tx.execute(
+                "UPDATE user_secrets SET slot = 'primary' WHERE owner = ?1 AND slot = 'secondary';",
+                [owner],
+            )?;
+        }
+        tx.commit()?;
+        Ok(retired > 0)
+    }
+
+    pub fn list_secrets(&self, owner: &str) -> Result<Vec<Credential>, rusqlite::Error> {
+        let conn = self.connect()?;
+        let mut stmt = conn.prepare(
+            "SELECT slot, secret, valid_from, valid_until FROM user_secrets
+                WHERE owner = ?1 ORDER BY slot;",
+        )?;
+        let rows = stmt.query_map([owner], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<i64>>(2)?,
+                row.get::<_, Option<i64>>(3)?,
+            ))
+        })?;
+        let mut credentials = Vec::new();
+        for row in rows {
+            let (slot, secret, valid_from, valid_until) = row?;
+            if let (Some(slot), Ok(secret)) =
+                (SecretSlot::parse(&slot), StoredSecret::parse(&secret))
+            {
+                credentials.push(Credential {
+                    slot,
+                    secret,
+                    valid_from: valid_from.map(|valid_from| valid_from as u64),
+                    valid_until: valid_until.map(|valid_until| valid_until as u64),
+                });
+            }
         }
+        Ok(credentials)
+    }
+
+    fn modified_at(&self) -> Option<SystemTime> {
+        modified_at(&[&self.path, &format!("{}-wal", self.path)])
+    }
+}
+
+fn user_exists(conn: &Connection, owner: &str) -> Result<bool, rusqlite::Error> {
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM users WHERE owner = ?1);",
+        [owner],
+        |row| row.get(0),
+    )
+}
+
+impl AuthBackend for SqliteAuthStore {
+    fn credentials<'a>(&'a self, owner: &'a str) -> AuthFuture<'a, Vec<Credential>> {
+        self.cache.credentials(owner)
+    }
+
+    fn knows_user<'a>(&'a self, owner: &'a str) -> AuthFuture<'a, bool> {
+        self.cache.knows_user(owner)
+    }
+
+    fn authorize<'a>(
+        &'a self,
+        owner: &'a str,
+        action: Action,
+        channel: &'a str,
+    ) -> AuthFuture<'a, Option<Rule>> {
+        self.cache.authorize(owner, action, channel)
+    }
+
+    fn quota<'a>(&'a self, owner: &'a str) -> AuthFuture<'a, Quota> {
+        self.cache.quota(owner)
+    }
+
+    fn networks<'a>(&'a self, owner: &'a str) -> AuthFuture<'a, Vec<NetworkRule>> {
+        self.cache.networks(owner)
+    }
+
+    fn reload(&self) -> AuthFuture<'_, Option<Vec<String>>> {
+        Box::pin(async move {
+            let modified = self.modified_at();
+            if *self.last_modified.lock().unwrap() == modified {
+                return Ok(None);
+            }
+            let auth_objects = self.load_auth_objects()?;
+            *self.last_modified.lock().unwrap() = modified;
+            Ok(self.cache.replace(auth_objects).await)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loads_role_grants_and_deny_rules() {
+        let path = std::env::temp_dir()
+            .join(format!("rust-feeds-{}-auth.db", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        let _ = std::fs::remove_file(&path);
+        let conn = SqliteAuthStore::open(&path).unwrap().connect().unwrap();
+        conn.execute_batch(
+            "INSERT INTO users (owner) VALUES ('kitek');
+            INSERT INTO user_secrets (owner, slot, secret) VALUES
+                ('kitek', 'primary', 'first'), ('kitek', 'secondary', 'second');
+            INSERT INTO channels (pattern) VALUES ('news.*'), ('news.secret');
+            INSERT INTO roles (role) VALUES ('reader');
+            INSERT INTO role_members (owner, role) VALUES ('kitek', 'reader');
+            INSERT INTO role_grants (role, pattern, action) VALUES ('reader', 'news.*', 'sub');
+            INSERT INTO grants (owner, pattern, action, effect)
+                VALUES ('kitek', 'news.secret', 'sub', 'deny');",
+        )
+        .unwrap();
+
+        let store = SqliteAuthStore::open(&path).unwrap();
+        smol::block_on(async {
+            let credentials = store.credentials("kitek").await.unwrap();
+            assert_eq!(credentials.len(), 2);
+            assert!(credentials[1].slot == SecretSlot::Secondary);
+
+            let role_rule = store.authorize("kitek", Action::Sub, "news.us").await;
+            let role_rule = role_rule.unwrap().unwrap();
+            assert!(role_rule.effect == Effect::Allow);
+            assert_eq!(role_rule.role.as_deref(), Some("reader"));
+            let denied = store.authorize("kitek", Action::Sub, "news.secret").await;
+            assert!(denied.unwrap().unwrap().effect == Effect::Deny);
+            let ungranted = store.authorize("kitek", Action::Pub, "news.us").await;
+            assert!(ungranted.unwrap().is_none());
+        });
+        std::fs::remove_file(&path).unwrap();
     }
 }
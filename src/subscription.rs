@@ -0,0 +1,233 @@
+use crate::audit::AuditPeer;
+use crate::backpressure::release_backpressure;
+use crate::channel_config::channel_config;
+use crate::dead_letter::{dead_letter, DropReason};
+use crate::expiry::{expire_message, is_expired};
+use crate::transport::ClientWriter;
+use smol::channel::{bounded, Receiver, Sender};
+use smol::{io::AsyncWriteExt, lock::Mutex};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Weak};
+
+static DEFAULT_MAX_QUEUE_DEPTH: usize = 16384;
+
+pub struct OutboundMessage {
+    pub frame: Vec<u8>,
+    pub expires_at: Option<u64>,
+    pub key: Option<String>,
+}
+
+#[derive(Default)]
+struct SubscriptionState {
+    queue: VecDeque<Arc<OutboundMessage>>,
+    credit: Option<u64>,
+    popped: u64,
+    keys: HashMap<String, u64>,
+}
+
+pub struct Subscription {
+    channel: String,
+    pub owner: String,
+    pub guest: bool,
+    pub writer: Arc<Mutex<ClientWriter>>,
+    state: Mutex<SubscriptionState>,
+    max_queue_depth: usize,
+    wake: Sender<()>,
+}
+
+impl OutboundMessage {
+    pub fn new(frame: Vec<u8>, expires_at: Option<u64>) -> OutboundMessage {
+        OutboundMessage {
+            frame,
+            expires_at,
+            key: None,
+        }
+    }
+}
+
+impl SubscriptionState {
+    fn slot(&self, key: &str) -> Option<usize> {
+        self.keys
+            .get(key)
+            .map(|sequence| (sequence - self.popped) as usize)
+    }
+
+    fn push(&mut self, message: Arc<OutboundMessage>) {
+        if let Some(key) = &message.key {
+            let sequence = self.popped + self.queue.len() as u64;
+            self.keys.insert(key.clone(), sequence);
+        }
+        self.queue.push_back(message);
+    }
+
+    fn pop(&mut self) -> Option<Arc<OutboundMessage>> {
+        let message = self.queue.pop_front()?;
+        if let Some(key) = &message.key {
+            if self.keys.get(key) == Some(&self.popped) {
+                self.keys.remove(key);
+            }
+        }
+        self.popped += 1;
+        Some(message)
+    }
+}
+
+impl Subscription {
+    pub async fn start(
+        channel: &str,
+        peer: &AuditPeer,
+        writer: Arc<Mutex<ClientWriter>>,
+        credit: Option<u64>,
+    ) -> Arc<Subscription> {
+        let max_queue_depth = channel_config(channel)
+            .await
+            .max_queue_depth
+            .unwrap_or(DEFAULT_MAX_QUEUE_DEPTH);
+        let (wake, woken) = bounded(1);
+        let subscription = Arc::new(Subscription {
+            channel: channel.to_owned(),
+            owner: peer.owner.clone().unwrap_or_default(),
+            guest: peer.guest,
+            writer,
+            state: Mutex::new(SubscriptionState {
+                credit,
+                ..Default::default()
+            }),
+            max_queue_depth,
+            wake,
+        });
+        smol::spawn(drain_queue(Arc::downgrade(&subscription), woken)).detach();
+        subscription
+    }
+
+    pub async fn queue_len(&self) -> usize {
+        self.state.lock().await.queue.len()
+    }
+
+    pub async fn deliver(&self, messages: &[Arc<OutboundMessage>]) -> Vec<Arc<OutboundMessage>> {
+        let mut overflow = Vec::new();
+        {
+            let mut state = self.state.lock().await;
+            for message in messages {
+                let slot = message.key.as_ref().and_then(|key| state.slot(key));
+                match slot {
+                    Some(slot) => state.queue[slot] = message.clone(),
+                    None if state.queue.len() >= self.max_queue_depth => {
+                        overflow.push(message.clone())
+                    }
+                    None => state.push(message.clone()),
+                }
+            }
+        }
+        let _ = self.wake.try_send(());
+        overflow
+    }
+
+    pub async fn replay(&self, messages: Vec<Arc<OutboundMessage>>) {
+        {
+            let mut state = self.state.lock().await;
+            for message in messages {
+                state.push(message);
+            }
+        }
+        let _ = self.wake.try_send(());
+    }
+
+    pub async fn grant_credit(&self, credit: u64) {
+        {
+            let mut state = self.state.lock().await;
+            state.credit = Some(state.credit.unwrap_or(0).saturating_add(credit));
+        }
+        let _ = self.wake.try_send(());
+    }
+
+    async fn take_batch(&self) -> (Vec<Arc<OutboundMessage>>, Vec<Arc<OutboundMessage>>) {
+        let mut state = self.state.lock().await;
+        let mut batch = Vec::new();
+        let mut expired = Vec::new();
+        while state.credit != Some(0) {
+            let Some(message) = state.pop() else {
+                break;
+            };
+            if is_expired(message.expires_at) {
+                expired.push(message);
+                continue;
+            }
+            batch.push(message);
+            if let Some(credit) = state.credit.as_mut() {
+                *credit -= 1;
+            }
+        }
+        (batch, expired)
+    }
+
+    async fn flush(&self) {
+        loop {
+            let (batch, expired) = self.take_batch().await;
+            for message in expired {
+                expire_message(&self.channel, &message.frame).await;
+            }
+            if batch.is_empty() {
+                break;
+            }
+
+            let data: Vec<u8> = batch
+                .iter()
+                .flat_map(|message| message.frame.iter().copied())
+                .collect();
+            let written = {
+                let mut stream = self.writer.lock().await;
+                stream.write_all(&data).await
+            };
+            if written.is_err() {
+                for message in batch {
+                    dead_letter(&self.channel, DropReason::DeliveryFailed, &message.frame).await;
+                }
+                break;
+            }
+        }
+        release_backpressure(&self.channel).await;
+    }
+}
+
+async fn drain_queue(subscription: Weak<Subscription>, woken: Receiver<()>) {
+    while woken.recv().await.is_ok() {
+        let Some(subscription) = subscription.upgrade() else {
+            break;
+        };
+        subscription.flush().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyed(key: &str) -> Arc<OutboundMessage> {
+        Arc::new(OutboundMessage {
+            frame: Vec::new(),
+            expires_at: None,
+            key: Some(key.to_owned()),
+        })
+    }
+
+    #[test]
+    fn key_slots_follow_the_queue() {
+        let mut state = SubscriptionState::default();
+        state.push(keyed("a"));
+        state.push(Arc::new(OutboundMessage::new(Vec::new(), None)));
+        state.push(keyed("b"));
+        assert_eq!(state.slot("a"), Some(0));
+        assert_eq!(state.slot("b"), Some(2));
+
+        state.pop();
+        assert_eq!(state.slot("a"), None);
+        assert_eq!(state.slot("b"), Some(1));
+
+        state.push(keyed("a"));
+        state.pop();
+        state.pop();
+        assert_eq!(state.slot("b"), None);
+        assert_eq!(state.slot("a"), Some(0));
+    }
+}
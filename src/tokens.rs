@@ -0,0 +1,133 @@
+use crate::authstore::channel_matches;
+use crate::config::CONFIG;
+use crate::scheduler::now_millis;
+use jsonwebtoken::{decode, decode_header, errors::ErrorKind, Algorithm, DecodingKey, Validation};
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use std::sync::Arc;
+
+#[derive(Deserialize)]
+pub struct TokenClaims {
+    pub sub: String,
+    pub exp: u64,
+    #[serde(default)]
+    pub allow_pub: Vec<String>,
+    #[serde(default)]
+    pub allow_sub: Vec<String>,
+}
+
+pub struct TokenGrant {
+    pub allow_pub: Vec<String>,
+    pub allow_sub: Vec<String>,
+    pub expires_at: u64,
+}
+
+pub struct TokenKey {
+    pub algorithms: Vec<Algorithm>,
+    pub key: DecodingKey,
+}
+
+lazy_static! {
+    pub static ref TOKEN_KEYS: Vec<TokenKey> = load_token_keys();
+}
+
+fn load_token_keys() -> Vec<TokenKey> {
+    let mut keys: Vec<TokenKey> = CONFIG
+        .token_hmac_secrets
+        .iter()
+        .map(|secret| TokenKey {
+            algorithms: vec![Algorithm::HS256, Algorithm::HS384, Algorithm::HS512],
+            key: DecodingKey::from_secret(secret.as_bytes()),
+        })
+        .collect();
+
+    for path in &CONFIG.token_public_keys {
+        let pem = match std::fs::read(path) {
+            Ok(pem) => pem,
+            Err(e) => {
+                println!("Failed to read token key {}: {}", path, e);
+                continue;
+            }
+        };
+        if let Ok(key) = DecodingKey::from_rsa_pem(&pem) {
+            keys.push(TokenKey {
+                algorithms: vec![
+                    Algorithm::RS256,
+                    Algorithm::RS384,
+                    Algorithm::RS512,
+                    Algorithm::PS256,
+                    Algorithm::PS384,
+                    Algorithm::PS512,
+                ],
+                key,
+            });
+        } else if let Ok(key) = DecodingKey::from_ed_pem(&pem) {
+            keys.push(TokenKey {
+                algorithms: vec![Algorithm::EdDSA],
+                key,
+            });
+        } else {
+            println!(
+                "Token key {} is neither an RSA nor an Ed25519 public key",
+                path
+            );
+        }
+    }
+    keys
+}
+
+pub fn verify_token(token: &str) -> Result<TokenClaims, jsonwebtoken::errors::Error> {
+    let header = decode_header(token)?;
+    let mut validation = Validation::new(header.alg);
+    validation.set_required_spec_claims(&["exp", "sub"]);
+    if let Some(issuer) = &CONFIG.token_issuer {
+        validation.set_issuer(&[issuer]);
+    }
+
+    let mut result = Err(ErrorKind::InvalidAlgorithm.into());
+    for token_key in TOKEN_KEYS
+        .iter()
+        .filter(|token_key| token_key.algorithms.contains(&header.alg))
+    {
+        result = decode::<TokenClaims>(token, &token_key.key, &validation).map(|data| data.claims);
+        if result.is_ok() {
+            break;
+        }
+    }
+    result
+}
+
+pub fn auth_token(token: &str) -> Option<(String, Arc<TokenGrant>)> {
+    let claims = match verify_token(token) {
+        Ok(claims) => claims,
+        Err(e) => {
+            println!("Rejected bearer token: {}", e);
+            return None;
+        }
+    };
+
+    let grant = TokenGrant {
+        allow_pub: claims.allow_pub,
+        allow_sub: claims.allow_sub,
+        expires_at: claims.exp.saturating_mul(1000),
+    };
+    Some((claims.sub, Arc::new(grant)))
+}
+
+impl TokenGrant {
+    pub fn allows_pub(&self, channel: &str) -> bool {
+        self.expires_at > now_millis()
+            && self
+                .allow_pub
+                .iter()
+                .any(|pattern| channel_matches(pattern, channel))
+    }
+
+    pub fn allows_sub(&self, channel: &str) -> bool {
+        self.expires_at > now_millis()
+            && self
+                .allow_sub
+                .iter()
+                .any(|pattern| channel_matches(pattern, channel))
+    }
+}
@@ -0,0 +1,276 @@
+use crate::config::BrokerConfig;
+use crate::errors::TlsError;
+use futures::io::{ReadHalf, WriteHalf};
+use futures_rustls::rustls::server::WebPkiClientVerifier;
+use futures_rustls::rustls::{RootCertStore, ServerConfig};
+use futures_rustls::server::TlsStream;
+use futures_rustls::TlsAcceptor;
+use smol::io::{AsyncRead, AsyncWrite};
+use smol::net::TcpStream;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::{Shutdown, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use x509_parser::extensions::GeneralName;
+
+pub enum ClientReader {
+    Plain(TcpStream),
+    Tls(ReadHalf<TlsStream<TcpStream>>),
+}
+
+pub struct ClientWriter {
+    tcp: TcpStream,
+    tls: Option<WriteHalf<TlsStream<TcpStream>>>,
+}
+
+pub enum ClientStream {
+    Plain(TcpStream),
+    Tls(TcpStream, Box<TlsStream<TcpStream>>),
+}
+
+pub fn plain_halves(stream: TcpStream) -> (ClientReader, ClientWriter) {
+    (
+        ClientReader::Plain(stream.clone()),
+        ClientWriter {
+            tcp: stream,
+            tls: None,
+        },
+    )
+}
+
+pub fn tls_halves(tcp: TcpStream, stream: TlsStream<TcpStream>) -> (ClientReader, ClientWriter) {
+    let (reader, writer) = futures::io::AsyncReadExt::split(stream);
+    (
+        ClientReader::Tls(reader),
+        ClientWriter {
+            tcp,
+            tls: Some(writer),
+        },
+    )
+}
+
+impl ClientStream {
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            ClientStream::Plain(tcp) | ClientStream::Tls(tcp, _) => tcp,
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> Result<(), std::io::Error> {
+        self.tcp().shutdown(how)
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr, std::io::Error> {
+        self.tcp().peer_addr()
+    }
+
+    pub fn into_halves(self) -> (ClientReader, ClientWriter) {
+        match self {
+            ClientStream::Plain(tcp) => plain_halves(tcp),
+            ClientStream::Tls(tcp, stream) => tls_halves(tcp, *stream),
+        }
+    }
+}
+
+impl ClientWriter {
+    pub fn shutdown(&self, how: Shutdown) -> Result<(), std::io::Error> {
+        self.tcp.shutdown(how)
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr, std::io::Error> {
+        self.tcp.peer_addr()
+    }
+}
+
+impl AsyncRead for ClientReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        match self.get_mut() {
+            ClientReader::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            ClientReader::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        match self.get_mut() {
+            ClientStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            ClientStream::Tls(_, stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        match self.get_mut() {
+            ClientStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            ClientStream::Tls(_, stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        match self.get_mut() {
+            ClientStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            ClientStream::Tls(_, stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        match self.get_mut() {
+            ClientStream::Plain(stream) => Pin::new(stream).poll_close(cx),
+            ClientStream::Tls(_, stream) => Pin::new(stream).poll_close(cx),
+        }
+    }
+}
+
+impl AsyncWrite for ClientWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        let writer = self.get_mut();
+        match &mut writer.tls {
+            Some(stream) => Pin::new(stream).poll_write(cx, buf),
+            None => Pin::new(&mut writer.tcp).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        let writer = self.get_mut();
+        match &mut writer.tls {
+            Some(stream) => Pin::new(stream).poll_flush(cx),
+            None => Pin::new(&mut writer.tcp).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        let writer = self.get_mut();
+        match &mut writer.tls {
+            Some(stream) => Pin::new(stream).poll_close(cx),
+            None => Pin::new(&mut writer.tcp).poll_close(cx),
+        }
+    }
+}
+
+pub fn tls_acceptor(config: &BrokerConfig) -> Result<Option<TlsAcceptor>, TlsError> {
+    let Some(cert_path) = &config.tls_cert else {
+        return Ok(None);
+    };
+    let Some(key_path) = &config.tls_key else {
+        return Err(TlsError::ConfigError(
+            "RUST_FEEDS_TLS_KEY is required with RUST_FEEDS_TLS_CERT".to_owned(),
+        ));
+    };
+    let required = match config.tls_client_auth.as_str() {
+        "required" => true,
+        "optional" => false,
+        other => {
+            return Err(TlsError::ConfigError(format!(
+                "RUST_FEEDS_TLS_CLIENT_AUTH must be optional or required, got {}",
+                other
+            )))
+        }
+    };
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path)?))?
+        .ok_or_else(|| TlsError::ConfigError(format!("No private key in {}", key_path)))?;
+
+    let builder = ServerConfig::builder();
+    let builder = match &config.tls_client_ca {
+        Some(client_ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for ca in rustls_pemfile::certs(&mut BufReader::new(File::open(client_ca_path)?)) {
+                roots.add(ca?)?;
+            }
+            let mut crls = Vec::new();
+            for crl_path in &config.tls_crls {
+                for crl in rustls_pemfile::crls(&mut BufReader::new(File::open(crl_path)?)) {
+                    crls.push(crl?);
+                }
+            }
+
+            let mut verifier = WebPkiClientVerifier::builder(Arc::new(roots)).with_crls(crls);
+            if !required {
+                verifier = verifier.allow_unauthenticated();
+            }
+            println!(
+                "TLS enabled, client certificates ({}) verified against {}",
+                config.tls_client_auth, client_ca_path
+            );
+            builder.with_client_cert_verifier(verifier.build()?)
+        }
+        None if required => {
+            return Err(TlsError::ConfigError(
+                "RUST_FEEDS_TLS_CLIENT_CA is required when client certificates are required"
+                    .to_owned(),
+            ))
+        }
+        None => {
+            println!("TLS enabled without client certificates");
+            builder.with_no_client_auth()
+        }
+    };
+    let server_config = builder.with_single_cert(certs, key)?;
+    Ok(Some(TlsAcceptor::from(Arc::new(server_config))))
+}
+
+pub fn has_client_certificate(stream: &TlsStream<TcpStream>) -> bool {
+    stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .is_some_and(|certs| !certs.is_empty())
+}
+
+pub fn certificate_identities(stream: &TlsStream<TcpStream>, source: &str) -> Vec<String> {
+    let Some(der) = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+    else {
+        return Vec::new();
+    };
+    let Ok((_, cert)) = x509_parser::parse_x509_certificate(der) else {
+        return Vec::new();
+    };
+
+    if source == "subject" {
+        return cert
+            .subject()
+            .iter_common_name()
+            .filter_map(|name| name.as_str().ok())
+            .map(str::to_owned)
+            .collect();
+    }
+    match cert.subject_alternative_name() {
+        Ok(Some(san)) => san
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::URI(name) | GeneralName::DNSName(name) => Some(name.to_string()),
+                GeneralName::RFC822Name(name) => Some(name.to_string()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}